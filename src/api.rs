@@ -0,0 +1,392 @@
+//! A JSON API to manage downloads, for scripts and other tools.
+//!
+//! The API is described by the OpenAPI document served at
+//! `/api/v1/openapi.json`. Keep it in sync when changing anything here.
+use std::collections::{BTreeMap, HashSet};
+
+use actix_web::{
+    delete, get,
+    http::StatusCode,
+    post,
+    web::{self, Data, Json, Path, Query, ReqData},
+    HttpResponse, Responder, ResponseError,
+};
+use ractor::{call, ActorRef};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    download_actor::{Coordinator, CoordinatorMsg, DuplicatePolicy, Enqueued},
+    extract::ExtractProgress,
+    hook::HookResult,
+    input_file::{self, BatchEntry},
+    proxy::ClientInfo,
+    store::{DownloadOptions, Progress},
+    users::{self, CurrentUser, QuotaError},
+};
+
+const OPENAPI: &str = include_str!("openapi.json");
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/api/v1")
+            // Report malformed requests as JSON too, like the rest of the API.
+            .app_data(
+                web::JsonConfig::default()
+                    .error_handler(|err, _| ApiError::BadRequest(err.to_string()).into()),
+            )
+            .app_data(
+                web::QueryConfig::default()
+                    .error_handler(|err, _| ApiError::BadRequest(err.to_string()).into()),
+            )
+            .service(openapi)
+            .service(list_downloads)
+            .service(get_download)
+            .service(create_download)
+            .service(retry_download)
+            .service(pause_download)
+            .service(delete_download),
+    );
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("No download with ID {0}")]
+    NotFound(String),
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("The URL is already being downloaded as {0}")]
+    Duplicate(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    Internal(String),
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Duplicate(_) => StatusCode::CONFLICT,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorResponse {
+            error: self.to_string(),
+        })
+    }
+}
+
+fn internal<E: ToString>(err: E) -> ApiError {
+    ApiError::Internal(err.to_string())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloadState {
+    /// Waiting for other downloads to finish.
+    Queued,
+    Downloading,
+    /// Downloaded, and extracting the archive.
+    Extracting,
+    Paused,
+    /// Gave up after too many retries.
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DownloadResponse {
+    pub id: String,
+    pub url: String,
+    /// The user who added the download.
+    pub owner: String,
+    pub state: DownloadState,
+    /// Bytes downloaded so far.
+    pub progress: u64,
+    /// Size of the file in bytes, if the server told us.
+    pub total: Option<u64>,
+    /// Bytes per second.
+    pub speed: f64,
+    pub out: Option<String>,
+    pub dir: Option<String>,
+    /// Names of the extra headers sent with the request. The values are left
+    /// out since they often hold credentials.
+    pub headers: Vec<String>,
+    pub started_at: Option<u64>,
+    /// Why the download failed, if it did.
+    pub error: Option<String>,
+    /// How the post-completion hook went, if it failed the download.
+    pub hook: Option<HookResult>,
+    /// Whether to extract the archive, if the download says.
+    pub extract: Option<bool>,
+    /// How far along extracting the archive is, while it is extracted.
+    pub extraction: Option<ExtractProgress>,
+}
+
+impl DownloadResponse {
+    pub fn new(progress: Progress, running: &HashSet<String>) -> Self {
+        let state = if progress.failed {
+            DownloadState::Failed
+        } else if progress.paused {
+            DownloadState::Paused
+        } else if running.contains(&progress.id) && progress.extracting.is_some() {
+            DownloadState::Extracting
+        } else if running.contains(&progress.id) {
+            DownloadState::Downloading
+        } else {
+            DownloadState::Queued
+        };
+        DownloadResponse {
+            state,
+            id: progress.id,
+            url: progress.url,
+            owner: progress.owner,
+            progress: progress.progress,
+            total: progress.total,
+            speed: progress.speed,
+            out: progress.options.out,
+            dir: progress.options.dir,
+            headers: progress
+                .options
+                .headers
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect(),
+            started_at: progress.started_at,
+            error: progress.error,
+            hook: progress.hook,
+            extract: progress.options.extract,
+            extraction: progress.extracting,
+        }
+    }
+}
+
+async fn running(coordinator: &ActorRef<Coordinator>) -> Result<HashSet<String>, ApiError> {
+    Ok(call!(coordinator, CoordinatorMsg::Running)
+        .map_err(internal)?
+        .into_iter()
+        .collect())
+}
+
+/// Get a download the user can see. Downloads of other users are reported as
+/// missing rather than forbidden, so their IDs aren't revealed.
+async fn find(
+    coordinator: &ActorRef<Coordinator>,
+    user: &CurrentUser,
+    id: &str,
+) -> Result<DownloadResponse, ApiError> {
+    let progress = call!(coordinator, CoordinatorMsg::Get, id.to_string())
+        .map_err(internal)?
+        .filter(|progress| user.can_see(&progress.owner))
+        .ok_or_else(|| ApiError::NotFound(id.to_string()))?;
+    Ok(DownloadResponse::new(
+        progress,
+        &running(coordinator).await?,
+    ))
+}
+
+/// Check that the user can change the download before acting on it.
+async fn check_manageable(
+    coordinator: &ActorRef<Coordinator>,
+    user: &CurrentUser,
+    id: &str,
+) -> Result<(), ApiError> {
+    let download = find(coordinator, user, id).await?;
+    if !user.can_manage(&download.owner) {
+        return Err(ApiError::Forbidden(format!(
+            "You are not allowed to change download {}",
+            id
+        )));
+    }
+    Ok(())
+}
+
+#[get("/openapi.json")]
+async fn openapi(client: ClientInfo) -> impl Responder {
+    // The server URL has to include the base path when running behind a proxy.
+    let document = OPENAPI.replacen(
+        r#""url": "/api/v1""#,
+        &format!(r#""url": "{}""#, client.url("/api/v1")),
+        1,
+    );
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(document)
+}
+
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+    state: Option<DownloadState>,
+    /// Only include downloads with this text in their URL.
+    q: Option<String>,
+}
+
+#[get("/downloads")]
+#[tracing::instrument(level = "debug", skip(coordinator))]
+async fn list_downloads(
+    query: Query<ListQuery>,
+    coordinator: Data<ActorRef<Coordinator>>,
+    user: ReqData<CurrentUser>,
+) -> Result<impl Responder, ApiError> {
+    let running = running(&coordinator).await?;
+    let needle = query.q.as_deref().unwrap_or_default().to_lowercase();
+    let downloads = call!(coordinator, CoordinatorMsg::List)
+        .map_err(internal)?
+        .into_iter()
+        .filter(|progress| user.can_see(&progress.owner))
+        .map(|progress| DownloadResponse::new(progress, &running))
+        .filter(|download| query.state.is_none_or(|state| download.state == state))
+        .filter(|download| download.url.to_lowercase().contains(&needle))
+        .collect::<Vec<_>>();
+    Ok(Json(downloads))
+}
+
+#[get("/downloads/{id}")]
+#[tracing::instrument(level = "debug", skip(coordinator))]
+async fn get_download(
+    id: Path<String>,
+    coordinator: Data<ActorRef<Coordinator>>,
+    user: ReqData<CurrentUser>,
+) -> Result<impl Responder, ApiError> {
+    Ok(Json(find(&coordinator, &user, &id).await?))
+}
+
+#[derive(Deserialize)]
+struct CreateDownloadRequest {
+    url: String,
+    out: Option<String>,
+    dir: Option<String>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    /// What to do if the URL is already being downloaded. Uses the server
+    /// default if not set.
+    duplicate: Option<DuplicatePolicy>,
+    /// Whether to extract the archive once it is downloaded. Uses the server
+    /// settings if not set.
+    extract: Option<bool>,
+}
+
+/// Header values are left out, as they are often credentials, and requests
+/// are logged.
+impl std::fmt::Debug for CreateDownloadRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CreateDownloadRequest")
+            .field("url", &self.url)
+            .field("out", &self.out)
+            .field("dir", &self.dir)
+            .field("headers", &self.headers.keys().collect::<Vec<_>>())
+            .field("duplicate", &self.duplicate)
+            .field("extract", &self.extract)
+            .finish()
+    }
+}
+
+#[post("/downloads")]
+#[tracing::instrument(level = "info", skip(coordinator))]
+async fn create_download(
+    request: Json<CreateDownloadRequest>,
+    coordinator: Data<ActorRef<Coordinator>>,
+    user: ReqData<CurrentUser>,
+) -> Result<impl Responder, ApiError> {
+    let request = request.into_inner();
+    let entry = BatchEntry {
+        url: input_file::parse_url(&request.url).map_err(ApiError::BadRequest)?,
+        options: DownloadOptions {
+            out: request
+                .out
+                .as_deref()
+                .map(input_file::validate_out)
+                .transpose()
+                .map_err(ApiError::BadRequest)?,
+            dir: request
+                .dir
+                .as_deref()
+                .map(input_file::validate_dir)
+                .transpose()
+                .map_err(ApiError::BadRequest)?,
+            headers: request
+                .headers
+                .iter()
+                .map(|(name, value)| input_file::validate_header(name, value))
+                .collect::<Result<_, _>>()
+                .map_err(ApiError::BadRequest)?,
+            extract: request.extract,
+        },
+    };
+
+    users::check_quota(&user, &coordinator)
+        .await
+        .map_err(|err| match err {
+            QuotaError::Exceeded { .. } => ApiError::Forbidden(err.to_string()),
+            QuotaError::Internal(_) => internal(err),
+        })?;
+
+    let result = call!(
+        coordinator,
+        CoordinatorMsg::Enqueue,
+        user.owner(),
+        vec![entry],
+        request.duplicate
+    )
+    .map_err(internal)?
+    .pop()
+    .ok_or_else(|| internal("The coordinator did not respond"))?;
+
+    let (status, id) = match result {
+        Enqueued::Accepted(id) => (StatusCode::CREATED, id),
+        Enqueued::Attached(id) => (StatusCode::OK, id),
+        Enqueued::Duplicate(id) => return Err(ApiError::Duplicate(id)),
+    };
+    let download = find(&coordinator, &user, &id).await?;
+    Ok(HttpResponse::build(status).json(download))
+}
+
+#[post("/downloads/{id}/retry")]
+#[tracing::instrument(level = "info", skip(coordinator))]
+async fn retry_download(
+    id: Path<String>,
+    coordinator: Data<ActorRef<Coordinator>>,
+    user: ReqData<CurrentUser>,
+) -> Result<impl Responder, ApiError> {
+    check_manageable(&coordinator, &user, &id).await?;
+    if !call!(coordinator, CoordinatorMsg::Retry, id.to_string()).map_err(internal)? {
+        return Err(ApiError::NotFound(id.to_string()));
+    }
+    Ok(Json(find(&coordinator, &user, &id).await?))
+}
+
+#[post("/downloads/{id}/pause")]
+#[tracing::instrument(level = "info", skip(coordinator))]
+async fn pause_download(
+    id: Path<String>,
+    coordinator: Data<ActorRef<Coordinator>>,
+    user: ReqData<CurrentUser>,
+) -> Result<impl Responder, ApiError> {
+    check_manageable(&coordinator, &user, &id).await?;
+    if !call!(coordinator, CoordinatorMsg::Pause, id.to_string()).map_err(internal)? {
+        return Err(ApiError::NotFound(id.to_string()));
+    }
+    Ok(Json(find(&coordinator, &user, &id).await?))
+}
+
+#[delete("/downloads/{id}")]
+#[tracing::instrument(level = "info", skip(coordinator))]
+async fn delete_download(
+    id: Path<String>,
+    coordinator: Data<ActorRef<Coordinator>>,
+    user: ReqData<CurrentUser>,
+) -> Result<impl Responder, ApiError> {
+    check_manageable(&coordinator, &user, &id).await?;
+    if !call!(coordinator, CoordinatorMsg::Delete, id.to_string()).map_err(internal)? {
+        return Err(ApiError::NotFound(id.to_string()));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,1020 @@
+//! Settings, from a TOML file, the environment and command-line flags.
+//!
+//! Each of these overrides the one before it, and all of them override the
+//! defaults. Secrets can't be passed as flags since other users on the machine
+//! could see them. They can be read from a file instead, by adding `_FILE` to
+//! the environment variable, which is how Docker secrets are mounted.
+//!
+//! Sending the process SIGHUP reads everything again. Settings that only
+//! matter when starting up, like the addresses to listen on, need a restart to
+//! change.
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use clap::{Args, Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    destination::{self, DestinationPolicy},
+    disk,
+    download_actor::DownloadSettings,
+    email::{self, EmailSettings},
+    extract::ExtractSettings,
+    hook::PostHook,
+    listen::{self, Listener},
+    login_throttle::ThrottleLimits,
+    mqtt::MqttSettings,
+    proxy::{self, ProxyConfig},
+    push::PushSettings,
+    sessions::LoginConfig,
+    tls::TlsFiles,
+    users,
+    webhooks::Webhook,
+};
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// A TOML configuration file.
+    #[arg(long, short, env = "HTTP_DROGUE_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Check the configuration and print it, without starting.
+    #[arg(long)]
+    pub check_config: bool,
+    #[command(flatten)]
+    pub overrides: Overrides,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Read a password from stdin and print its hash, for
+    /// `HTTP_DROGUE_PASSWORD_HASH`.
+    HashPassword,
+}
+
+/// Settings from the environment and flags. Flags take precedence, which clap
+/// takes care of.
+#[derive(Debug, Args)]
+pub struct Overrides {
+    /// Where to keep downloads in progress, the history and accounts.
+    #[arg(long, env = "STORE_PATH")]
+    store_path: Option<String>,
+    /// Addresses to listen on, `unix:` followed by a path for a Unix socket.
+    #[arg(long, env = "HTTP_DROGUE_LISTEN", value_delimiter = ',')]
+    listen: Option<Vec<String>>,
+    /// How many threads handle requests.
+    #[arg(long, env = "HTTP_DROGUE_WORKERS")]
+    workers: Option<usize>,
+    /// A PEM file with the certificate chain, to serve HTTPS.
+    #[arg(long, env = "HTTP_DROGUE_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+    /// A PEM file with the private key of the certificate.
+    #[arg(long, env = "HTTP_DROGUE_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+    /// The path to serve the app under, behind a reverse proxy.
+    #[arg(long, env = "HTTP_DROGUE_BASE_PATH")]
+    base_path: Option<String>,
+    /// Proxies allowed to set the X-Forwarded headers.
+    #[arg(long, env = "HTTP_DROGUE_TRUSTED_PROXIES", value_delimiter = ',')]
+    trusted_proxies: Option<Vec<String>>,
+    /// The username of the admin account.
+    #[arg(long, env = "HTTP_DROGUE_ADMIN_USER")]
+    admin_user: Option<String>,
+    /// How browsers log in, basic or form.
+    #[arg(long, env = "HTTP_DROGUE_LOGIN")]
+    login: Option<String>,
+    /// How long sessions from the login page last.
+    #[arg(long, env = "HTTP_DROGUE_SESSION_LIFETIME")]
+    session_lifetime: Option<String>,
+    /// How many failed logins lock out an address or username.
+    #[arg(long, env = "HTTP_DROGUE_LOGIN_MAX_FAILURES")]
+    login_max_failures: Option<u32>,
+    /// How long failed logins are remembered.
+    #[arg(long, env = "HTTP_DROGUE_LOGIN_FAILURE_WINDOW")]
+    login_failure_window: Option<String>,
+    /// How long a lockout lasts.
+    #[arg(long, env = "HTTP_DROGUE_LOGIN_LOCKOUT")]
+    login_lockout: Option<String>,
+    /// How many files to download at once.
+    #[arg(long, env = "HTTP_DROGUE_CONCURRENT_DOWNLOADS")]
+    concurrent_downloads: Option<usize>,
+    /// How many times to restart a failed download before giving up.
+    #[arg(long, env = "HTTP_DROGUE_MAX_RETRIES")]
+    max_retries: Option<u64>,
+    /// How often to save the progress of running downloads.
+    #[arg(long, env = "HTTP_DROGUE_CHECKPOINT_INTERVAL")]
+    checkpoint_interval: Option<String>,
+    /// What to do with a URL that is already being downloaded: reject,
+    /// attach or allow.
+    #[arg(long, env = "HTTP_DROGUE_DUPLICATE_POLICY")]
+    duplicate_policy: Option<String>,
+    /// How much space to keep free on the disk, like 1GiB. Downloads are
+    /// held while there is less.
+    #[arg(long, env = "HTTP_DROGUE_MIN_FREE_SPACE")]
+    min_free_space: Option<String>,
+    /// Filesystems to preallocate downloads on, like ext4 or xfs.
+    #[arg(long, env = "HTTP_DROGUE_PREALLOCATE", value_delimiter = ',')]
+    preallocate: Option<Vec<String>>,
+    /// Give completed files the modification time the server sent.
+    #[arg(long, env = "HTTP_DROGUE_PRESERVE_MTIME")]
+    preserve_mtime: Option<bool>,
+    /// Record the URL files came from in their extended attributes.
+    #[arg(long, env = "HTTP_DROGUE_ORIGIN_XATTR")]
+    origin_xattr: Option<bool>,
+    /// Hosts and networks downloads may connect to, even if they are private.
+    #[arg(long, env = "HTTP_DROGUE_ALLOW_DESTINATIONS", value_delimiter = ',')]
+    allow_destinations: Option<Vec<String>>,
+    /// Hosts and networks downloads may not connect to.
+    #[arg(long, env = "HTTP_DROGUE_DENY_DESTINATIONS", value_delimiter = ',')]
+    deny_destinations: Option<Vec<String>>,
+    /// A shell command to run after each download completes.
+    #[arg(long, env = "HTTP_DROGUE_HOOK_COMMAND")]
+    hook_command: Option<String>,
+    /// How long the hook may run before it is killed.
+    #[arg(long, env = "HTTP_DROGUE_HOOK_TIMEOUT")]
+    hook_timeout: Option<String>,
+    /// Mark the download as failed if the hook fails.
+    #[arg(long, env = "HTTP_DROGUE_HOOK_FAIL_DOWNLOAD")]
+    hook_fail_download: Option<bool>,
+    /// Serve Prometheus metrics at /metrics.
+    #[arg(long, env = "HTTP_DROGUE_METRICS")]
+    metrics: Option<bool>,
+    /// An MQTT broker to publish to, like mqtt://localhost:1883.
+    #[arg(long, env = "HTTP_DROGUE_MQTT_BROKER")]
+    mqtt_broker: Option<String>,
+    /// The port of the MQTT broker, instead of the one in its URL.
+    #[arg(long, env = "HTTP_DROGUE_MQTT_PORT")]
+    mqtt_port: Option<u16>,
+    /// Who we connect to the MQTT broker as. Has to be unique on the broker.
+    #[arg(long, env = "HTTP_DROGUE_MQTT_CLIENT_ID")]
+    mqtt_client_id: Option<String>,
+    /// The username to connect to the MQTT broker with.
+    #[arg(long, env = "HTTP_DROGUE_MQTT_USERNAME")]
+    mqtt_username: Option<String>,
+    /// Put in front of all the MQTT topics.
+    #[arg(long, env = "HTTP_DROGUE_MQTT_TOPIC_PREFIX")]
+    mqtt_topic_prefix: Option<String>,
+    /// How often to publish the combined download speed to MQTT.
+    #[arg(long, env = "HTTP_DROGUE_MQTT_THROUGHPUT_INTERVAL")]
+    mqtt_throughput_interval: Option<String>,
+    /// Take commands from the MQTT command topic. Anyone who can publish to
+    /// the broker can then do anything the admin can.
+    #[arg(long, env = "HTTP_DROGUE_MQTT_COMMANDS")]
+    mqtt_commands: Option<bool>,
+    /// The mail server to send notifications through.
+    #[arg(long, env = "HTTP_DROGUE_SMTP_SERVER")]
+    smtp_server: Option<String>,
+    /// The port of the mail server, if not the default for the TLS mode.
+    #[arg(long, env = "HTTP_DROGUE_SMTP_PORT")]
+    smtp_port: Option<u16>,
+    /// How to secure the connection to the mail server: starttls, tls or
+    /// none.
+    #[arg(long, env = "HTTP_DROGUE_SMTP_TLS")]
+    smtp_tls: Option<String>,
+    /// The username to log in to the mail server with.
+    #[arg(long, env = "HTTP_DROGUE_SMTP_USERNAME")]
+    smtp_username: Option<String>,
+    /// Who notifications are sent from.
+    #[arg(long, env = "HTTP_DROGUE_EMAIL_FROM")]
+    email_from: Option<String>,
+    /// Who notifications are sent to.
+    #[arg(long, env = "HTTP_DROGUE_EMAIL_TO", value_delimiter = ',')]
+    email_to: Option<Vec<String>>,
+    /// Email when a download completes.
+    #[arg(long, env = "HTTP_DROGUE_EMAIL_ON_COMPLETED")]
+    email_on_completed: Option<bool>,
+    /// Email when a download fails.
+    #[arg(long, env = "HTTP_DROGUE_EMAIL_ON_FAILED")]
+    email_on_failed: Option<bool>,
+    /// When to send the daily digest, like 08:00 in UTC.
+    #[arg(long, env = "HTTP_DROGUE_EMAIL_DIGEST")]
+    email_digest: Option<String>,
+    /// Who runs the server, as a mailto: or https: URL, for push services to
+    /// get in touch. Turns on browser push notifications.
+    #[arg(long, env = "HTTP_DROGUE_PUSH_CONTACT")]
+    push_contact: Option<String>,
+    /// Kinds of archives to extract once downloaded: zip, tar, tar.gz or
+    /// tar.xz.
+    #[arg(long, env = "HTTP_DROGUE_EXTRACT_AUTO", value_delimiter = ',')]
+    extract_auto: Option<Vec<String>>,
+    /// Delete archives once they are extracted.
+    #[arg(long, env = "HTTP_DROGUE_EXTRACT_DELETE_ARCHIVE")]
+    extract_delete_archive: Option<bool>,
+    /// Don't extract archives that unpack into more than this many times
+    /// their own size.
+    #[arg(long, env = "HTTP_DROGUE_EXTRACT_MAX_RATIO")]
+    extract_max_ratio: Option<u64>,
+    /// Don't extract archives with more files than this.
+    #[arg(long, env = "HTTP_DROGUE_EXTRACT_MAX_FILES")]
+    extract_max_files: Option<u64>,
+}
+
+/// The configuration as written, before checking it. Durations are written
+/// like `15m` or `12h`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub store_path: String,
+    pub server: ServerSection,
+    pub auth: AuthSection,
+    pub downloads: DownloadsSection,
+    pub hook: HookSection,
+    pub extract: ExtractSection,
+    pub metrics: MetricsSection,
+    pub mqtt: MqttSection,
+    pub email: EmailSection,
+    pub push: PushSection,
+    pub webhooks: Vec<WebhookSection>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    pub listen: Vec<String>,
+    pub workers: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_cert: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_key: Option<PathBuf>,
+    pub base_path: String,
+    pub trusted_proxies: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSection {
+    pub admin_user: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
+    /// Hashed when the configuration is loaded, `password_hash` is better.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    pub login: String,
+    pub session_lifetime: String,
+    pub max_failures: u32,
+    pub failure_window: String,
+    pub lockout: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DownloadsSection {
+    pub concurrent: usize,
+    pub max_retries: u64,
+    pub checkpoint_interval: String,
+    pub duplicate_policy: String,
+    /// Like `1GiB`, or 0 to only stop when the disk is full.
+    pub min_free_space: String,
+    /// Filesystem types like `ext4` or `xfs`. Nothing is preallocated if
+    /// empty.
+    pub preallocate: Vec<String>,
+    /// Use `Last-Modified` as the modification time of completed files.
+    pub preserve_mtime: bool,
+    /// Write `user.xdg.origin.url` and `user.xdg.referrer.url`.
+    pub origin_xattr: bool,
+    pub allow_destinations: Vec<String>,
+    pub deny_destinations: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HookSection {
+    /// Run with the shell after each download completes. No hook if empty.
+    pub command: String,
+    pub timeout: String,
+    pub fail_download: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExtractSection {
+    /// zip, tar, tar.gz or tar.xz. Nothing is extracted unless the download
+    /// asks for it if empty.
+    pub auto: Vec<String>,
+    pub delete_archive: bool,
+    /// Extraction fails if the files add up to more than this many times the
+    /// size of the archive.
+    pub max_ratio: u64,
+    pub max_files: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsSection {
+    pub enabled: bool,
+    /// If set, Prometheus has to send it as a bearer token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttSection {
+    /// Like `mqtt://localhost:1883`. Nothing is published if empty.
+    pub broker: String,
+    /// The port to use instead of the one in `broker`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    pub client_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    pub topic_prefix: String,
+    pub throughput_interval: String,
+    /// Take commands from the command topic, as the admin.
+    pub commands: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmailSection {
+    /// The mail server. No emails are sent if empty.
+    pub smtp_server: String,
+    /// The default for `smtp_tls` if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub smtp_port: Option<u16>,
+    /// starttls, tls or none.
+    pub smtp_tls: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub smtp_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub smtp_password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    pub on_completed: bool,
+    pub on_failed: bool,
+    /// When to send the daily digest, like `08:00` in UTC. No digest if
+    /// empty.
+    pub digest: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PushSection {
+    /// Like `mailto:admin@example.com`. No push notifications if empty.
+    pub contact: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookSection {
+    pub url: String,
+    /// added, completed, failed or retried. All of them if empty.
+    pub events: Vec<String>,
+    /// Signs the payloads if set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    /// A file to read the secret from instead.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret_file: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            store_path: "sqlite:///data/http-drogue.sqlite".to_string(),
+            server: ServerSection::default(),
+            auth: AuthSection::default(),
+            downloads: DownloadsSection::default(),
+            hook: HookSection::default(),
+            extract: ExtractSection::default(),
+            metrics: MetricsSection::default(),
+            mqtt: MqttSection::default(),
+            email: EmailSection::default(),
+            push: PushSection::default(),
+            webhooks: Vec::new(),
+        }
+    }
+}
+
+impl Default for ServerSection {
+    fn default() -> Self {
+        ServerSection {
+            listen: vec!["0.0.0.0:8080".to_string()],
+            workers: 1,
+            tls_cert: None,
+            tls_key: None,
+            base_path: String::new(),
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+impl Default for AuthSection {
+    fn default() -> Self {
+        let login = LoginConfig::default();
+        let limits = ThrottleLimits::default();
+        AuthSection {
+            admin_user: "admin".to_string(),
+            password_hash: None,
+            password: None,
+            login: "basic".to_string(),
+            session_lifetime: humantime::format_duration(login.session_lifetime).to_string(),
+            max_failures: limits.max_failures,
+            failure_window: humantime::format_duration(limits.window).to_string(),
+            lockout: humantime::format_duration(limits.lockout).to_string(),
+        }
+    }
+}
+
+impl Default for DownloadsSection {
+    fn default() -> Self {
+        DownloadsSection {
+            concurrent: 1,
+            max_retries: 24,
+            checkpoint_interval: "30s".to_string(),
+            duplicate_policy: "reject".to_string(),
+            min_free_space: "1GiB".to_string(),
+            preallocate: Vec::new(),
+            preserve_mtime: true,
+            origin_xattr: true,
+            allow_destinations: Vec::new(),
+            deny_destinations: Vec::new(),
+        }
+    }
+}
+
+impl Default for HookSection {
+    fn default() -> Self {
+        HookSection {
+            command: String::new(),
+            timeout: "10m".to_string(),
+            fail_download: false,
+        }
+    }
+}
+
+impl Default for ExtractSection {
+    fn default() -> Self {
+        ExtractSection {
+            auto: Vec::new(),
+            delete_archive: false,
+            max_ratio: 100,
+            max_files: 100_000,
+        }
+    }
+}
+
+impl Default for MqttSection {
+    fn default() -> Self {
+        MqttSection {
+            broker: String::new(),
+            port: None,
+            client_id: "http-drogue".to_string(),
+            username: None,
+            password: None,
+            topic_prefix: "http-drogue".to_string(),
+            throughput_interval: "10s".to_string(),
+            commands: false,
+        }
+    }
+}
+
+impl Default for EmailSection {
+    fn default() -> Self {
+        EmailSection {
+            smtp_server: String::new(),
+            smtp_port: None,
+            smtp_tls: "starttls".to_string(),
+            smtp_username: None,
+            smtp_password: None,
+            from: String::new(),
+            to: Vec::new(),
+            on_completed: true,
+            on_failed: true,
+            digest: String::new(),
+        }
+    }
+}
+
+/// The configuration after checking it, ready to use.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub store_path: String,
+    pub listeners: Vec<Listener>,
+    pub workers: usize,
+    pub tls: Option<TlsFiles>,
+    pub proxy: ProxyConfig,
+    pub admin_user: String,
+    pub admin_password_hash: String,
+    pub login: LoginConfig,
+    pub throttle: ThrottleLimits,
+    pub downloads: DownloadSettings,
+    pub metrics_enabled: bool,
+    pub metrics_token: Option<String>,
+    pub mqtt: Option<MqttSettings>,
+    pub email: Option<EmailSettings>,
+    pub push: Option<PushSettings>,
+    pub webhooks: Vec<Webhook>,
+}
+
+/// Read a secret from the environment variable, or from the file named in the
+/// variable with `_FILE` added.
+fn secret_from_env(variable: &str) -> Result<Option<String>, String> {
+    let file_variable = format!("{}_FILE", variable);
+    match (env::var(variable), env::var_os(&file_variable)) {
+        (Ok(_), Some(_)) => Err(format!("Set either {} or {}", variable, file_variable)),
+        (Ok(value), None) => Ok(Some(value)),
+        (Err(_), Some(path)) => fs::read_to_string(&path)
+            .map(|value| Some(value.trim_end_matches(['\r', '\n']).to_string()))
+            .map_err(|err| {
+                let path = Path::new(&path).display();
+                format!("Failed to read {} {}: {}", file_variable, path, err)
+            }),
+        (Err(_), None) => Ok(None),
+    }
+}
+
+fn parse_duration(key: &str, value: &str) -> Result<Duration, String> {
+    humantime::parse_duration(value).map_err(|err| format!("Invalid {}: {}", key, err))
+}
+
+fn with_key<T>(key: &str, result: Result<T, String>) -> Result<T, String> {
+    result.map_err(|err| format!("Invalid {}: {}", key, err))
+}
+
+impl Config {
+    /// Read the configuration file if there is one, then apply the
+    /// environment and flags on top.
+    pub fn load(cli: &Cli) -> Result<Config, String> {
+        let mut config = match &cli.config {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+                toml::from_str(&text).map_err(|err| format!("In {}: {}", path.display(), err))?
+            }
+            None => Config::default(),
+        };
+
+        // Setting either one replaces both, so a password from the
+        // environment isn't overruled by a hash from the file.
+        let password_hash = secret_from_env("HTTP_DROGUE_PASSWORD_HASH")?;
+        let password = secret_from_env("HTTP_DROGUE_PASSWORD")?;
+        if password_hash.is_some() || password.is_some() {
+            config.auth.password_hash = password_hash;
+            config.auth.password = password;
+        }
+        if let Some(token) = secret_from_env("HTTP_DROGUE_METRICS_TOKEN")? {
+            config.metrics.token = Some(token);
+        }
+        if let Some(password) = secret_from_env("HTTP_DROGUE_MQTT_PASSWORD")? {
+            config.mqtt.password = Some(password);
+        }
+        if let Some(password) = secret_from_env("HTTP_DROGUE_SMTP_PASSWORD")? {
+            config.email.smtp_password = Some(password);
+        }
+        for webhook in &mut config.webhooks {
+            if let Some(path) = &webhook.secret_file {
+                let secret = fs::read_to_string(path)
+                    .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+                webhook.secret = Some(secret.trim_end_matches(['\r', '\n']).to_string());
+            }
+        }
+
+        let overrides = &cli.overrides;
+        let server = &mut config.server;
+        let auth = &mut config.auth;
+        let downloads = &mut config.downloads;
+        macro_rules! set {
+            ($($target:expr => $value:expr),* $(,)?) => {
+                $(if let Some(value) = &$value {
+                    $target = value.clone();
+                })*
+            };
+        }
+        set! {
+            config.store_path => overrides.store_path,
+            server.listen => overrides.listen,
+            server.workers => overrides.workers,
+            server.base_path => overrides.base_path,
+            server.trusted_proxies => overrides.trusted_proxies,
+            auth.admin_user => overrides.admin_user,
+            auth.login => overrides.login,
+            auth.session_lifetime => overrides.session_lifetime,
+            auth.max_failures => overrides.login_max_failures,
+            auth.failure_window => overrides.login_failure_window,
+            auth.lockout => overrides.login_lockout,
+            downloads.concurrent => overrides.concurrent_downloads,
+            downloads.max_retries => overrides.max_retries,
+            downloads.checkpoint_interval => overrides.checkpoint_interval,
+            downloads.duplicate_policy => overrides.duplicate_policy,
+            downloads.min_free_space => overrides.min_free_space,
+            downloads.preallocate => overrides.preallocate,
+            downloads.preserve_mtime => overrides.preserve_mtime,
+            downloads.origin_xattr => overrides.origin_xattr,
+            downloads.allow_destinations => overrides.allow_destinations,
+            downloads.deny_destinations => overrides.deny_destinations,
+            config.hook.command => overrides.hook_command,
+            config.hook.timeout => overrides.hook_timeout,
+            config.hook.fail_download => overrides.hook_fail_download,
+            config.extract.auto => overrides.extract_auto,
+            config.extract.delete_archive => overrides.extract_delete_archive,
+            config.extract.max_ratio => overrides.extract_max_ratio,
+            config.extract.max_files => overrides.extract_max_files,
+            config.metrics.enabled => overrides.metrics,
+            config.mqtt.broker => overrides.mqtt_broker,
+            config.mqtt.client_id => overrides.mqtt_client_id,
+            config.mqtt.topic_prefix => overrides.mqtt_topic_prefix,
+            config.mqtt.throughput_interval => overrides.mqtt_throughput_interval,
+            config.mqtt.commands => overrides.mqtt_commands,
+            config.push.contact => overrides.push_contact,
+        }
+        let email = &mut config.email;
+        set! {
+            email.smtp_server => overrides.smtp_server,
+            email.smtp_tls => overrides.smtp_tls,
+            email.from => overrides.email_from,
+            email.to => overrides.email_to,
+            email.on_completed => overrides.email_on_completed,
+            email.on_failed => overrides.email_on_failed,
+            email.digest => overrides.email_digest,
+        }
+        if overrides.mqtt_port.is_some() {
+            config.mqtt.port = overrides.mqtt_port;
+        }
+        if overrides.mqtt_username.is_some() {
+            config.mqtt.username = overrides.mqtt_username.clone();
+        }
+        if overrides.smtp_port.is_some() {
+            email.smtp_port = overrides.smtp_port;
+        }
+        if overrides.smtp_username.is_some() {
+            email.smtp_username = overrides.smtp_username.clone();
+        }
+        if overrides.tls_cert.is_some() {
+            server.tls_cert = overrides.tls_cert.clone();
+        }
+        if overrides.tls_key.is_some() {
+            server.tls_key = overrides.tls_key.clone();
+        }
+        Ok(config)
+    }
+
+    /// Check every setting, returning the first problem found.
+    pub fn validate(&self) -> Result<Settings, String> {
+        let server = &self.server;
+        let auth = &self.auth;
+        let downloads = &self.downloads;
+
+        let listeners = with_key(
+            "server.listen",
+            listen::parse_listeners(&server.listen.join(",")),
+        )?;
+        if server.workers == 0 {
+            return Err("Invalid server.workers: there has to be at least one".to_string());
+        }
+        let tls = match (&server.tls_cert, &server.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsFiles {
+                cert: cert.clone(),
+                key: key.clone(),
+            }),
+            (None, None) => None,
+            _ => return Err("Set both server.tls_cert and server.tls_key to use HTTPS".to_string()),
+        };
+        let proxy = ProxyConfig {
+            base_path: with_key(
+                "server.base_path",
+                proxy::normalize_base_path(&server.base_path),
+            )?,
+            trusted: Arc::default(),
+        };
+        proxy.set_trusted(with_key(
+            "server.trusted_proxies",
+            proxy::parse_trusted(&server.trusted_proxies.join(",")),
+        )?);
+
+        if let Err(err) = users::validate_username(&auth.admin_user) {
+            return Err(format!("Invalid auth.admin_user: {}", err));
+        }
+        let admin_password_hash = match (&auth.password_hash, &auth.password) {
+            (Some(hash), _) => {
+                with_key("auth.password_hash", users::validate_hash(hash))?;
+                hash.clone()
+            }
+            (None, Some(password)) => users::hash_password(password)?,
+            (None, None) => {
+                return Err("No password set for the admin account, please set auth.password_hash or the HTTP_DROGUE_PASSWORD_HASH environment variable".to_string())
+            }
+        };
+        let login = LoginConfig {
+            mode: auth.login.parse()?,
+            session_lifetime: parse_duration("auth.session_lifetime", &auth.session_lifetime)?,
+        };
+        let throttle = ThrottleLimits {
+            max_failures: auth.max_failures.max(1),
+            window: parse_duration("auth.failure_window", &auth.failure_window)?,
+            lockout: parse_duration("auth.lockout", &auth.lockout)?,
+        };
+
+        if downloads.concurrent == 0 {
+            return Err("Invalid downloads.concurrent: there has to be at least one".to_string());
+        }
+        let destinations = DestinationPolicy {
+            allow: with_key(
+                "downloads.allow_destinations",
+                destination::parse_rules(&downloads.allow_destinations.join(",")),
+            )?,
+            deny: with_key(
+                "downloads.deny_destinations",
+                destination::parse_rules(&downloads.deny_destinations.join(",")),
+            )?,
+        };
+        let hook = match self.hook.command.trim() {
+            "" => None,
+            command => Some(PostHook {
+                command: command.to_string(),
+                timeout: parse_duration("hook.timeout", &self.hook.timeout)?,
+                fail_download: self.hook.fail_download,
+            }),
+        };
+        let downloads = DownloadSettings {
+            concurrent_downloads: downloads.concurrent,
+            max_retries: downloads.max_retries,
+            duplicate_policy: downloads.duplicate_policy.parse()?,
+            checkpoint_interval: parse_duration(
+                "downloads.checkpoint_interval",
+                &downloads.checkpoint_interval,
+            )?,
+            destinations: Arc::new(destinations),
+            hook,
+            extract: self.extract_settings()?,
+            min_free_space: with_key(
+                "downloads.min_free_space",
+                disk::parse_size(&downloads.min_free_space),
+            )?,
+            preallocate: with_key(
+                "downloads.preallocate",
+                disk::parse_filesystems(&downloads.preallocate),
+            )?,
+            preserve_mtime: downloads.preserve_mtime,
+            origin_xattr: downloads.origin_xattr,
+        };
+
+        let mqtt = self.mqtt_settings()?;
+        let email = self.email_settings()?;
+        let push = self.push_settings(&downloads.destinations)?;
+
+        let webhooks = self
+            .webhooks
+            .iter()
+            .map(|webhook| {
+                let url = reqwest::Url::parse(&webhook.url)
+                    .ok()
+                    .filter(|url| matches!(url.scheme(), "http" | "https"))
+                    .ok_or_else(|| format!("Invalid webhooks.url: {}", webhook.url))?;
+                let events = webhook
+                    .events
+                    .iter()
+                    .map(|event| with_key("webhooks.events", event.parse()))
+                    .collect::<Result<_, _>>()?;
+                Ok(Webhook {
+                    url,
+                    events,
+                    secret: webhook.secret.clone().filter(|secret| !secret.is_empty()),
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(Settings {
+            store_path: self.store_path.clone(),
+            listeners,
+            workers: server.workers,
+            tls,
+            proxy,
+            admin_user: auth.admin_user.clone(),
+            admin_password_hash,
+            login,
+            throttle,
+            downloads,
+            metrics_enabled: self.metrics.enabled,
+            metrics_token: self.metrics.token.clone().filter(|token| !token.is_empty()),
+            mqtt,
+            email,
+            push,
+            webhooks,
+        })
+    }
+
+    fn extract_settings(&self) -> Result<ExtractSettings, String> {
+        let extract = &self.extract;
+        if extract.max_ratio == 0 {
+            return Err("Invalid extract.max_ratio: it has to be at least 1".to_string());
+        }
+        if extract.max_files == 0 {
+            return Err("Invalid extract.max_files: it has to be at least 1".to_string());
+        }
+        Ok(ExtractSettings {
+            auto: extract
+                .auto
+                .iter()
+                .map(|kind| with_key("extract.auto", kind.parse()))
+                .collect::<Result<_, _>>()?,
+            delete_archive: extract.delete_archive,
+            max_ratio: extract.max_ratio,
+            max_files: extract.max_files,
+        })
+    }
+
+    fn mqtt_settings(&self) -> Result<Option<MqttSettings>, String> {
+        let mqtt = &self.mqtt;
+        if mqtt.broker.is_empty() {
+            return Ok(None);
+        }
+        let url = reqwest::Url::parse(&mqtt.broker)
+            .ok()
+            .filter(|url| url.scheme() == "mqtt")
+            .ok_or_else(|| {
+                format!(
+                    "Invalid mqtt.broker: {}, expected something like mqtt://localhost:1883",
+                    mqtt.broker
+                )
+            })?;
+        let host = match url.host_str() {
+            // IPv6 addresses without the brackets.
+            Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+            None => return Err(format!("Invalid mqtt.broker: {} has no host", mqtt.broker)),
+        };
+        let topic_prefix = mqtt.topic_prefix.trim_end_matches('/');
+        if topic_prefix.is_empty() || topic_prefix.contains(['+', '#']) {
+            return Err(format!(
+                "Invalid mqtt.topic_prefix: {}, it can't be empty or have wildcards",
+                mqtt.topic_prefix
+            ));
+        }
+        let throughput_interval =
+            parse_duration("mqtt.throughput_interval", &mqtt.throughput_interval)?;
+        if throughput_interval.is_zero() {
+            return Err("Invalid mqtt.throughput_interval: it can't be zero".to_string());
+        }
+        Ok(Some(MqttSettings {
+            host: host.to_string(),
+            port: mqtt.port.or(url.port()).unwrap_or(1883),
+            client_id: mqtt.client_id.clone(),
+            username: mqtt
+                .username
+                .clone()
+                .filter(|username| !username.is_empty()),
+            password: mqtt.password.clone(),
+            topic_prefix: topic_prefix.to_string(),
+            throughput_interval,
+            commands: mqtt.commands,
+        }))
+    }
+
+    fn email_settings(&self) -> Result<Option<EmailSettings>, String> {
+        let email = &self.email;
+        if email.smtp_server.is_empty() {
+            return Ok(None);
+        }
+        let from = email
+            .from
+            .parse()
+            .map_err(|err| format!("Invalid email.from: {}: {}", email.from, err))?;
+        let to = email
+            .to
+            .iter()
+            .map(|to| {
+                to.parse()
+                    .map_err(|err| format!("Invalid email.to: {}: {}", to, err))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if to.is_empty() {
+            return Err("Invalid email.to: there has to be at least one recipient".to_string());
+        }
+        let digest_at = match email.digest.as_str() {
+            "" => None,
+            time => Some(with_key("email.digest", email::parse_time_of_day(time))?),
+        };
+        Ok(Some(EmailSettings {
+            server: email.smtp_server.clone(),
+            port: email.smtp_port,
+            tls: with_key("email.smtp_tls", email.smtp_tls.parse())?,
+            username: email
+                .smtp_username
+                .clone()
+                .filter(|username| !username.is_empty()),
+            password: email.smtp_password.clone(),
+            from,
+            to,
+            on_completed: email.on_completed,
+            on_failed: email.on_failed,
+            digest_at,
+        }))
+    }
+
+    fn push_settings(
+        &self,
+        destinations: &Arc<DestinationPolicy>,
+    ) -> Result<Option<PushSettings>, String> {
+        let contact = &self.push.contact;
+        if contact.is_empty() {
+            return Ok(None);
+        }
+        if !(contact.starts_with("mailto:") || contact.starts_with("https://")) {
+            return Err(format!(
+                "Invalid push.contact: {}, expected a mailto: or https: URL",
+                contact
+            ));
+        }
+        Ok(Some(PushSettings {
+            contact: contact.clone(),
+            destinations: destinations.clone(),
+        }))
+    }
+
+    /// The configuration as TOML, with secrets left out.
+    pub fn to_redacted_toml(&self) -> String {
+        let mut config = self.clone();
+        for secret in [
+            &mut config.auth.password_hash,
+            &mut config.auth.password,
+            &mut config.metrics.token,
+            &mut config.mqtt.password,
+            &mut config.email.smtp_password,
+        ]
+        .into_iter()
+        .chain(
+            config
+                .webhooks
+                .iter_mut()
+                .map(|webhook| &mut webhook.secret),
+        ) {
+            if secret.is_some() {
+                *secret = Some("<redacted>".to_string());
+            }
+        }
+        toml::to_string_pretty(&config).expect("The configuration can always be written as TOML")
+    }
+}
+
+impl Settings {
+    /// Settings that can't change without a restart, and differ between the
+    /// two.
+    pub fn needs_restart(&self, other: &Settings) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.store_path != other.store_path {
+            changed.push("store_path");
+        }
+        if self.listeners != other.listeners {
+            changed.push("server.listen");
+        }
+        if self.workers != other.workers {
+            changed.push("server.workers");
+        }
+        if self.tls.as_ref().map(|tls| (&tls.cert, &tls.key))
+            != other.tls.as_ref().map(|tls| (&tls.cert, &tls.key))
+        {
+            changed.push("server.tls_cert");
+        }
+        if self.proxy.base_path != other.proxy.base_path {
+            changed.push("server.base_path");
+        }
+        if self.admin_user != other.admin_user {
+            changed.push("auth.admin_user");
+        }
+        if self.login.mode != other.login.mode {
+            changed.push("auth.login");
+        }
+        if self.login.session_lifetime != other.login.session_lifetime {
+            changed.push("auth.session_lifetime");
+        }
+        if self.metrics_enabled != other.metrics_enabled {
+            changed.push("metrics.enabled");
+        }
+        if self.mqtt != other.mqtt {
+            changed.push("mqtt");
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mqtt_config(throughput_interval: &str) -> Config {
+        let mut config = Config::default();
+        config.mqtt.broker = "mqtt://localhost:1883".to_string();
+        config.mqtt.throughput_interval = throughput_interval.to_string();
+        config
+    }
+
+    #[test]
+    fn mqtt_throughput_interval_is_not_zero() {
+        let err = mqtt_config("0s").mqtt_settings().unwrap_err();
+        assert!(
+            err.starts_with("Invalid mqtt.throughput_interval"),
+            "{}",
+            err
+        );
+        let settings = mqtt_config("5s").mqtt_settings().unwrap().unwrap();
+        assert_eq!(settings.throughput_interval, Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,85 @@
+//! Protection against other sites submitting our forms.
+//!
+//! Browsers send the logged in user's credentials along with forms that other
+//! sites submit to us. Every form we render carries a token that other sites
+//! can't know, and the handlers refuse submissions without it. The token is a
+//! signature of who is logged in, so there is nothing extra to store.
+use actix_web::error::ErrorForbidden;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{
+    store::{SecretStore, StoreError},
+    tokens,
+};
+
+/// The signing key, kept in the store so forms keep working after a restart.
+#[derive(Clone)]
+pub struct CsrfKey {
+    key: String,
+}
+
+impl std::fmt::Debug for CsrfKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CsrfKey").finish_non_exhaustive()
+    }
+}
+
+impl CsrfKey {
+    /// Load the key, creating it the first time.
+    pub async fn load(secrets: &SecretStore) -> Result<Self, StoreError> {
+        let key = match secrets.get("csrf").await? {
+            Some(key) => key,
+            None => {
+                let key = tokens::random_secret();
+                secrets.put("csrf", &key).await?;
+                key
+            }
+        };
+        Ok(CsrfKey { key })
+    }
+
+    /// The token for forms shown to `subject`, which identifies the login.
+    pub fn token_for(&self, subject: &str) -> Csrf {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.key.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(subject.as_bytes());
+        let token = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Csrf::Token(token)
+    }
+}
+
+/// What forms submitted with this request need to carry. The authentication
+/// middleware adds this to every request it lets through.
+#[derive(Debug, Clone)]
+pub enum Csrf {
+    /// Requests made with an API token. Browsers never add those on their own.
+    NotNeeded,
+    Token(String),
+}
+
+impl Csrf {
+    /// The token to put in forms.
+    pub fn token(&self) -> &str {
+        match self {
+            Csrf::NotNeeded => "",
+            Csrf::Token(token) => token,
+        }
+    }
+
+    /// Refuse the submission if it doesn't carry the right token.
+    pub fn check(&self, submitted: &str) -> actix_web::Result<()> {
+        match self {
+            Csrf::NotNeeded => Ok(()),
+            Csrf::Token(token) if tokens::constant_time_eq(token, submitted) => Ok(()),
+            Csrf::Token(_) => Err(ErrorForbidden(
+                "The form has expired, reload the page and try again",
+            )),
+        }
+    }
+}
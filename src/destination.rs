@@ -0,0 +1,412 @@
+//! Which servers downloads may be fetched from.
+//!
+//! Anyone who can add downloads could otherwise make the server fetch pages
+//! from the local network, like a router's admin page, a cloud provider's
+//! metadata endpoint or a service only listening on localhost, and then read
+//! them from the downloads folder. Loopback, link-local and private addresses
+//! are blocked unless they are allowed explicitly.
+//!
+//! Host names are checked after they are resolved, on every redirect too, so
+//! a public name pointing at a private address doesn't get around the policy.
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+
+use hyper::client::connect::dns::Name;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    redirect, Client, Url,
+};
+
+/// How many redirects to follow, same as the reqwest default.
+const MAX_REDIRECTS: usize = 10;
+
+/// A host name or address range in the allow or deny lists.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rule {
+    /// Matches the host and all of its subdomains.
+    Host(String),
+    Net(IpNet),
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(net) = s.parse::<IpNet>() {
+            return Ok(Rule::Net(net.trunc()));
+        }
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(Rule::Net(ip.into()));
+        }
+        let host = s.trim_start_matches('.').to_lowercase();
+        let valid = !host.is_empty()
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.'));
+        if !valid {
+            return Err(format!(
+                "Invalid destination {}, expected a host name, an IP address or a CIDR range",
+                s
+            ));
+        }
+        Ok(Rule::Host(host))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Downloading from {0} is not allowed")]
+pub struct Blocked(pub String);
+
+#[derive(Debug, Clone, Default)]
+pub struct DestinationPolicy {
+    /// Destinations that can be downloaded from, even if they are private.
+    pub allow: Vec<Rule>,
+    /// Destinations that can never be downloaded from. These win over `allow`.
+    pub deny: Vec<Rule>,
+}
+
+/// Parse a list of rules separated by commas or whitespace.
+pub fn parse_rules(list: &str) -> Result<Vec<Rule>, String> {
+    list.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| rule.parse())
+        .collect()
+}
+
+fn matches_host(rules: &[Rule], host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_lowercase();
+    rules.iter().any(|rule| match rule {
+        Rule::Host(name) => {
+            host == *name
+                || host
+                    .strip_suffix(name.as_str())
+                    .is_some_and(|rest| rest.ends_with('.'))
+        }
+        Rule::Net(_) => false,
+    })
+}
+
+fn matches_ip(rules: &[Rule], ip: IpAddr) -> bool {
+    rules.iter().any(|rule| match rule {
+        Rule::Host(_) => false,
+        Rule::Net(net) => net.contains(&ip),
+    })
+}
+
+/// IPv4 addresses written as IPv6 are checked as the IPv4 address they are.
+/// Besides mapped addresses like `::ffff:127.0.0.1`, that includes the
+/// deprecated IPv4-compatible ones like `::127.0.0.1`, and the address a NAT64
+/// (`64:ff9b::/96`) or 6to4 (`2002::/16`) gateway would forward to.
+fn canonical(ip: IpAddr) -> IpAddr {
+    let IpAddr::V6(v6) = ip else {
+        return ip;
+    };
+    if let Some(v4) = v6.to_ipv4_mapped() {
+        return IpAddr::V4(v4);
+    }
+    let [a, b, c, d, e, f, g, h] = v6.segments();
+    let embedded = match [a, b, c, d, e, f] {
+        // `::` and `::1` are the IPv6 unspecified and loopback addresses.
+        [0, 0, 0, 0, 0, 0] if !(g == 0 && h <= 1) => Some((g, h)),
+        [0x64, 0xff9b, 0, 0, 0, 0] => Some((g, h)),
+        [0x2002, ..] => Some((b, c)),
+        _ => None,
+    };
+    match embedded {
+        Some((high, low)) => IpAddr::V4(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low))),
+        None => ip,
+    }
+}
+
+/// IPv4 ranges that aren't covered by the `Ipv4Addr` methods: shared address
+/// space for carrier-grade NAT, IETF protocol assignments, benchmarking, and
+/// the reserved range the broadcast address is in.
+const RESERVED_V4: [&str; 4] = [
+    "100.64.0.0/10",
+    "192.0.0.0/24",
+    "198.18.0.0/15",
+    "240.0.0.0/4",
+];
+
+/// Unique local, link-local and the deprecated site-local IPv6 ranges.
+const RESERVED_V6: [&str; 3] = ["fc00::/7", "fe80::/10", "fec0::/10"];
+
+/// Whether the address is reachable from the internet at large, rather than
+/// only from this machine or the local network.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            !(v4.is_loopback()
+                || v4.is_private()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_multicast()
+                || v4.octets()[0] == 0
+                || RESERVED_V4
+                    .iter()
+                    .any(|net| net.parse::<Ipv4Net>().unwrap().contains(&v4)))
+        }
+        IpAddr::V6(v6) => {
+            !(v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                || RESERVED_V6
+                    .iter()
+                    .any(|net| net.parse::<Ipv6Net>().unwrap().contains(&v6)))
+        }
+    }
+}
+
+impl DestinationPolicy {
+    /// Check a URL before connecting to it. Addresses in the URL are checked
+    /// here, host names once they are resolved.
+    pub fn check_url(&self, url: &Url) -> Result<(), Blocked> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(Blocked(url.to_string()));
+        }
+        let Some(host) = url.host_str() else {
+            return Err(Blocked(url.to_string()));
+        };
+        // IPv6 addresses are written in brackets in URLs.
+        let address = host.trim_start_matches('[').trim_end_matches(']');
+        match address.parse::<IpAddr>() {
+            Ok(ip) => self.check_ip(None, ip),
+            Err(_) if matches_host(&self.deny, host) => Err(Blocked(host.to_string())),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Check an address that `host` resolved to, or an address from a URL.
+    pub fn check_ip(&self, host: Option<&str>, ip: IpAddr) -> Result<(), Blocked> {
+        let ip = canonical(ip);
+        let allowed = !matches_ip(&self.deny, ip)
+            && (host.is_some_and(|host| matches_host(&self.allow, host))
+                || matches_ip(&self.allow, ip)
+                || is_public(ip));
+        if allowed {
+            Ok(())
+        } else {
+            Err(Blocked(match host {
+                Some(host) => format!("{} ({})", host, ip),
+                None => ip.to_string(),
+            }))
+        }
+    }
+
+    /// An HTTP client that only connects where the policy allows. Proxies
+    /// from `HTTP_PROXY` and the like are ignored, as they would resolve the
+    /// host names instead of us.
+    pub fn client(self: &Arc<Self>) -> reqwest::Result<Client> {
+        let policy = self.clone();
+        Client::builder()
+            .no_proxy()
+            .dns_resolver(Arc::new(PolicyResolver {
+                policy: self.clone(),
+            }))
+            .redirect(redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    return attempt.error("too many redirects");
+                }
+                match policy.check_url(attempt.url()) {
+                    Ok(()) => attempt.follow(),
+                    Err(err) => attempt.error(err),
+                }
+            }))
+            .build()
+    }
+}
+
+/// Resolves host names, leaving out the addresses the policy blocks.
+struct PolicyResolver {
+    policy: Arc<DestinationPolicy>,
+}
+
+impl Resolve for PolicyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let policy = self.policy.clone();
+        Box::pin(async move {
+            let host = name.as_str();
+            if matches_host(&policy.deny, host) {
+                return Err(Blocked(host.to_string()).into());
+            }
+            let mut blocked = None;
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, 0))
+                .await?
+                .filter(|addr| match policy.check_ip(Some(host), addr.ip()) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        blocked = Some(err);
+                        false
+                    }
+                })
+                .collect();
+            match (addrs.is_empty(), blocked) {
+                (true, Some(err)) => Err(err.into()),
+                _ => Ok(Box::new(addrs.into_iter()) as Addrs),
+            }
+        })
+    }
+}
+
+/// Whether a download failed because the policy blocked it, as opposed to
+/// something that might work if tried again.
+pub fn is_blocked(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if err.is::<Blocked>() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn blocks_non_public_ipv4() {
+        for blocked in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "0.1.2.3",
+            "100.64.0.1",
+            "192.0.0.8",
+            "198.18.0.1",
+            "198.19.255.255",
+            "224.0.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+        ] {
+            assert!(!is_public(ip(blocked)), "{} should be blocked", blocked);
+        }
+        for public in ["1.1.1.1", "93.184.216.34", "198.20.0.1", "100.128.0.1"] {
+            assert!(is_public(ip(public)), "{} should be public", public);
+        }
+    }
+
+    #[test]
+    fn blocks_non_public_ipv6() {
+        for blocked in [
+            "::1",
+            "::",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "fec0::1",
+            "ff02::1",
+        ] {
+            assert!(!is_public(ip(blocked)), "{} should be blocked", blocked);
+        }
+        assert!(is_public(ip("2606:4700::1111")));
+    }
+
+    #[test]
+    fn canonical_unwraps_embedded_ipv4() {
+        let metadata = ip("169.254.169.254");
+        assert_eq!(canonical(ip("::ffff:169.254.169.254")), metadata);
+        assert_eq!(canonical(ip("::169.254.169.254")), metadata);
+        assert_eq!(canonical(ip("64:ff9b::a9fe:a9fe")), metadata);
+        assert_eq!(canonical(ip("2002:a9fe:a9fe::1")), metadata);
+        // Not IPv4 addresses in disguise.
+        assert_eq!(canonical(ip("::1")), ip("::1"));
+        assert_eq!(canonical(ip("::")), ip("::"));
+        assert_eq!(canonical(ip("2606:4700::1111")), ip("2606:4700::1111"));
+        assert_eq!(canonical(ip("10.0.0.1")), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn blocks_embedded_ipv4_urls() {
+        let policy = DestinationPolicy::default();
+        for blocked in [
+            "http://[::ffff:127.0.0.1]/",
+            "http://[::127.0.0.1]/",
+            "http://[64:ff9b::a9fe:a9fe]/",
+            "http://[2002:c0a8:101::1]/",
+            "http://[::1]:8080/",
+            "http://[fe80::1]/",
+        ] {
+            assert!(
+                policy.check_url(&url(blocked)).is_err(),
+                "{} should be blocked",
+                blocked
+            );
+        }
+        assert!(policy.check_url(&url("http://[2606:4700::1111]/")).is_ok());
+        assert!(policy.check_url(&url("http://[64:ff9b::101:101]/")).is_ok());
+        assert!(policy.check_url(&url("https://1.1.1.1/")).is_ok());
+    }
+
+    #[test]
+    fn blocks_other_schemes() {
+        let policy = DestinationPolicy::default();
+        assert!(policy.check_url(&url("ftp://example.com/file")).is_err());
+        assert!(policy.check_url(&url("file:///etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn matches_ip_ranges() {
+        let rules = parse_rules("192.168.1.0/24, 10.0.0.7 fd00::/8").unwrap();
+        assert!(matches_ip(&rules, ip("192.168.1.200")));
+        assert!(matches_ip(&rules, ip("10.0.0.7")));
+        assert!(matches_ip(&rules, ip("fd00::1")));
+        assert!(!matches_ip(&rules, ip("192.168.2.1")));
+        assert!(!matches_ip(&rules, ip("10.0.0.8")));
+    }
+
+    #[test]
+    fn matches_hosts_and_subdomains() {
+        let rules = parse_rules("example.com,.Home.Arpa").unwrap();
+        assert!(matches_host(&rules, "example.com"));
+        assert!(matches_host(&rules, "files.example.com"));
+        assert!(matches_host(&rules, "EXAMPLE.COM."));
+        assert!(matches_host(&rules, "nas.home.arpa"));
+        assert!(!matches_host(&rules, "badexample.com"));
+        assert!(!matches_host(&rules, "example.com.evil.net"));
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        assert!(parse_rules("http://example.com").is_err());
+        assert!(parse_rules("10.0.0.0/33").is_err());
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let policy = DestinationPolicy {
+            allow: parse_rules("192.168.0.0/16,nas.home.arpa").unwrap(),
+            deny: parse_rules("192.168.1.1,example.com").unwrap(),
+        };
+        assert!(policy.check_ip(None, ip("192.168.5.5")).is_ok());
+        assert!(policy.check_ip(None, ip("::ffff:192.168.5.5")).is_ok());
+        assert!(policy.check_ip(None, ip("192.168.1.1")).is_err());
+        assert!(policy.check_ip(None, ip("::ffff:192.168.1.1")).is_err());
+        assert!(policy
+            .check_ip(Some("nas.home.arpa"), ip("10.0.0.2"))
+            .is_ok());
+        assert!(policy
+            .check_ip(Some("nas.home.arpa"), ip("192.168.1.1"))
+            .is_err());
+        assert!(policy.check_url(&url("http://www.example.com/")).is_err());
+        assert!(policy.check_url(&url("http://[::ffff:c0a8:101]/")).is_err());
+        assert!(policy.check_ip(Some("example.org"), ip("1.1.1.1")).is_ok());
+    }
+}
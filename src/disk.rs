@@ -0,0 +1,186 @@
+//! Free space on the disk downloads are saved to.
+//!
+//! A full disk makes every running download fail the same way, and retrying
+//! them doesn't help until space is freed up. So downloads that are too large
+//! for the space left are paused before they start, and the coordinator holds
+//! the whole queue while free space is below `downloads.min_free_space`.
+//!
+//! On Linux, downloads of a known size can be preallocated, so large files
+//! aren't fragmented and a full disk shows up before anything is downloaded.
+//! That helps on filesystems like ext4 and XFS, but not on copy-on-write ones
+//! like Btrfs and ZFS, so it is only done on the filesystems listed in
+//! `downloads.preallocate`.
+use std::{io, path::Path};
+
+use tokio::fs::File;
+
+/// Where downloads in progress are written, before they are moved into the
+/// folder of their owner.
+pub const TEMP_DIR: &str = ".";
+
+/// Filesystems that can be picked for preallocation, along with the magic
+/// number `statfs` reports for them. ext2 and ext3 count as ext4.
+pub const FILESYSTEMS: [(&str, u64); 7] = [
+    ("ext4", 0xEF53),
+    ("xfs", 0x5846_5342),
+    ("btrfs", 0x9123_683E),
+    ("f2fs", 0xF2F5_2010),
+    ("zfs", 0x2FC1_2FC1),
+    ("tmpfs", 0x0102_1994),
+    ("nfs", 0x6969),
+];
+
+/// The queue is held because the disk is nearly full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LowDisk {
+    /// Free space when it was last checked, in bytes.
+    pub free: u64,
+    /// The queue continues once there is more than this.
+    pub min_free_space: u64,
+}
+
+/// The closest folder to the path that exists, which decides what filesystem
+/// it is on.
+fn existing_parent(path: &Path) -> &Path {
+    path.ancestors()
+        .map(|ancestor| {
+            if ancestor.as_os_str().is_empty() {
+                Path::new(TEMP_DIR)
+            } else {
+                ancestor
+            }
+        })
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(Path::new(TEMP_DIR))
+}
+
+/// How many bytes can be written to the filesystem the path is on. Folders
+/// that don't exist yet go by the closest parent that does.
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    statvfs(existing_parent(path))
+}
+
+/// Whether the two paths are on the same filesystem, so moving a file from one
+/// to the other doesn't take any space. If it can't be told, they are taken
+/// to be on different ones.
+#[cfg(unix)]
+pub fn same_filesystem(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let device = |path| std::fs::metadata(existing_parent(path)).map(|meta| meta.dev());
+    matches!((device(a), device(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+#[cfg(not(unix))]
+pub fn same_filesystem(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn statvfs(path: &Path) -> io::Result<u64> {
+    let path = c_path(path)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Blocks reserved for root don't count, we likely can't use them.
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(unix)]
+fn c_path(path: &Path) -> io::Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+#[cfg(not(unix))]
+fn statvfs(_path: &Path) -> io::Result<u64> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// The name of the filesystem the path is on, if it is one of
+/// [`FILESYSTEMS`].
+#[cfg(target_os = "linux")]
+pub fn filesystem(path: &Path) -> io::Result<Option<&'static str>> {
+    let path = c_path(existing_parent(path))?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The magic numbers fit in 32 bits, but the type is signed on some
+    // platforms.
+    let magic = stat.f_type as u64 & 0xFFFF_FFFF;
+    Ok(FILESYSTEMS
+        .iter()
+        .find(|(_, filesystem)| *filesystem == magic)
+        .map(|(name, _)| *name))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn filesystem(_path: &Path) -> io::Result<Option<&'static str>> {
+    Ok(None)
+}
+
+/// Reserve space for the whole file up front. This also makes the file that
+/// long.
+#[cfg(target_os = "linux")]
+pub fn preallocate(file: &File, size: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let size = libc::off_t::try_from(size)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    if unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, size) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn preallocate(_file: &File, _size: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Check that the names in `downloads.preallocate` are filesystems we know.
+pub fn parse_filesystems(names: &[String]) -> Result<Vec<String>, String> {
+    names
+        .iter()
+        .map(|name| {
+            let name = name.trim().to_lowercase();
+            if FILESYSTEMS.iter().any(|(known, _)| *known == name) {
+                Ok(name)
+            } else {
+                Err(format!(
+                    "Unknown filesystem {}, expected one of {}",
+                    name,
+                    FILESYSTEMS.map(|(known, _)| known).join(", ")
+                ))
+            }
+        })
+        .collect()
+}
+
+/// Parse a size like `500MiB` or `2GiB`. A plain number is in bytes.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("{} is not a size like 500MiB or 2GiB", size))?;
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KiB" => 1 << 10,
+        "M" | "MiB" => 1 << 20,
+        "G" | "GiB" => 1 << 30,
+        "T" | "TiB" => 1 << 40,
+        unit => {
+            return Err(format!(
+                "Unknown unit {}, expected B, KiB, MiB, GiB or TiB",
+                unit
+            ))
+        }
+    };
+    Ok((number * multiplier as f64) as u64)
+}
@@ -1,24 +1,41 @@
-use std::collections::HashMap;
-use std::time::Instant;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use futures::{future, StreamExt};
+use futures::StreamExt;
 use lazy_static::lazy_static;
 use ractor::{
-    concurrency::JoinHandle, Actor, ActorId, ActorProcessingErr, ActorRef, SupervisionEvent,
+    cast, concurrency::JoinHandle, Actor, ActorId, ActorProcessingErr, ActorRef, RpcReplyPort,
+    SupervisionEvent,
 };
 use regex::Regex;
-use reqwest::Client;
+use reqwest::{Client, Url};
 use sanitize_filename::sanitize;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use tokio::{
-    fs::{self, OpenOptions},
-    io::AsyncWriteExt,
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
 };
 use tracing::{debug, error, info, warn};
 use ulid::Ulid;
 
 use crate::{
     avg_range::MovingAverage,
-    store::{DownloadProgressStore, Progress},
+    destination::{self, DestinationPolicy},
+    disk::{self, LowDisk},
+    email::Notifier,
+    events::{DownloadEvent, Event, EventBus},
+    extract::{self, ArchiveKind, ExtractError, ExtractProgress, ExtractSettings},
+    hook::{HookResult, PostHook},
+    input_file::BatchEntry,
+    metrics, origin,
+    push::Push,
+    store::{unix_now, DownloadProgressStore, HistoryEntry, HistoryStore, Progress},
+    webhooks::{WebhookEvent, Webhooks},
 };
 
 pub fn url_to_filename(url: &str) -> String {
@@ -26,60 +43,288 @@ pub fn url_to_filename(url: &str) -> String {
         // Find the last segment of the URL, discarding any query parameters
         static ref RE: Regex = Regex::new(r#"/([^?/]+)([?].*)?$"#).unwrap();
     }
-    RE.captures(&url)
+    RE.captures(url)
         .and_then(|v| v.get(1))
         .map(|v| v.as_str().to_string())
-        .unwrap_or_else(|| sanitize(&url))
+        .unwrap_or_else(|| sanitize(url))
 }
 
-#[derive(Debug)]
+/// Where a finished download should be placed, relative to the download folder.
+pub fn final_path(record: &Progress) -> PathBuf {
+    let options = &record.options;
+    let name = options
+        .out
+        .clone()
+        .unwrap_or_else(|| url_to_filename(&record.url));
+    let mut path = PathBuf::from(&record.base_dir);
+    if let Some(dir) = &options.dir {
+        path.push(dir);
+    }
+    path.push(name);
+    path
+}
+
+/// How often to check the free space on the disk.
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(10);
 
+#[derive(Debug)]
 pub struct Coordinator {
+    /// The settings to start with. They can be changed later with
+    /// `CoordinatorMsg::Reconfigure`.
+    pub settings: DownloadSettings,
+
+    pub store: DownloadProgressStore,
+    pub history: HistoryStore,
+    pub events: EventBus,
+    pub webhooks: Webhooks,
+    pub notifier: Notifier,
+    pub push: Push,
+}
+
+/// The settings of the coordinator that can be changed while it runs.
+#[derive(Debug, Clone)]
+pub struct DownloadSettings {
     /// How many files to download at once. The coordinator will launch this many
     /// downloaders.
     pub concurrent_downloads: usize,
 
-    pub store: DownloadProgressStore,
+    /// How many times a failed download is restarted before giving up.
+    pub max_retries: u64,
+
+    /// What to do when asked to download a URL that is already being downloaded.
+    pub duplicate_policy: DuplicatePolicy,
+
+    /// How often to save the progress of running downloads to the store.
+    /// State changes are saved right away.
+    pub checkpoint_interval: Duration,
+
+    /// Where downloads may be fetched from.
+    pub destinations: Arc<DestinationPolicy>,
+
+    /// A command to run after each download completes.
+    pub hook: Option<PostHook>,
+
+    /// Which archives to extract once they are downloaded, and how.
+    pub extract: ExtractSettings,
+
+    /// Bytes to keep free on the disk. Downloads that would go past it don't
+    /// start, and the queue is held while there is less.
+    pub min_free_space: u64,
+
+    /// Filesystems to preallocate downloads of a known size on.
+    pub preallocate: Vec<String>,
+
+    /// Give completed files the modification time the server sent.
+    pub preserve_mtime: bool,
+    /// Record the URL a file came from in its extended attributes.
+    pub origin_xattr: bool,
 }
 
-#[derive(Debug)]
+/// What to do when a URL is submitted while a download for it is still active.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicatePolicy {
+    /// Don't start another download.
+    #[default]
+    Reject,
+    /// Don't start another download, and point the requester at the
+    /// existing one instead.
+    Attach,
+    /// Download the URL again, as a separate download.
+    Allow,
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = String;
 
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "attach" => Ok(Self::Attach),
+            "allow" => Ok(Self::Allow),
+            _ => Err(format!(
+                "Unknown duplicate policy {}, expected reject, attach or allow",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct CoordinatorState {
     pub children: HashMap<ActorId, DownloaderRef>,
+    /// IDs of downloads waiting for one of the downloaders to free up.
+    pub queue: VecDeque<String>,
+    /// Every download that hasn't completed, by ID. This is kept up to date
+    /// as downloads progress, while the store only gets a checkpoint now and
+    /// then.
+    pub downloads: BTreeMap<String, Progress>,
+    pub settings: DownloadSettings,
+    /// Shared by all downloads, and only connects where the settings allow.
+    pub client: Client,
+    /// Set while the queue is held because the disk is nearly full.
+    pub low_disk: Option<LowDisk>,
+}
+
+impl CoordinatorState {
+    fn update_metrics(&self) {
+        let count = |f: fn(&Progress) -> bool| self.downloads.values().filter(|d| f(d)).count();
+        for (state, value) in [
+            ("active", self.children.len()),
+            ("queued", self.queue.len()),
+            ("paused", count(|download| download.paused)),
+            ("failed", count(|download| download.failed)),
+        ] {
+            metrics::DOWNLOADS
+                .with_label_values(&[state])
+                .set(value as i64);
+        }
+        let speed = self
+            .children
+            .values()
+            .filter_map(|child| self.downloads.get(&child.download_id))
+            .fold(0.0, |total, download| total + download.speed);
+        metrics::THROUGHPUT.set(speed);
+    }
+
+    /// Whether the download is being downloaded, or is waiting to be.
+    fn is_active(&self, id: &str) -> bool {
+        self.queue.iter().any(|queued| queued == id)
+            || self.children.values().any(|child| child.download_id == id)
+    }
 }
 
 #[derive(Debug)]
 pub struct DownloaderRef {
     pub id: ActorId,
-    pub url: String,
+    pub download_id: String,
     pub actor: ActorRef<Downloader>,
     pub handle: JoinHandle<()>,
     pub retries: u64,
+    /// When the progress of this download was last saved to the store.
+    pub last_checkpoint: Instant,
 }
 
+/// Who new downloads belong to, and where they go.
 #[derive(Debug, Clone)]
-pub struct StartDownload {
-    pub url: String,
+pub struct Owner {
+    pub username: String,
+    /// The owner's folder, relative to the download folder.
+    pub base_dir: String,
 }
 
-#[derive(Debug, Clone)]
+/// What happened to a download that was submitted to the coordinator. Each
+/// variant holds the ID of the download that will fetch the URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Enqueued {
+    Accepted(String),
+    /// There is already a download for this URL, and the duplicate policy
+    /// rejected the new one.
+    Duplicate(String),
+    /// There is already a download for this URL, and the new one was
+    /// attached to it.
+    Attached(String),
+}
+
+#[derive(Debug)]
 pub enum CoordinatorMsg {
-    StartDownload(StartDownload),
+    /// Restart a download that failed or was paused. Replies with false if
+    /// there is no such download.
+    Retry(String, RpcReplyPort<bool>),
+    /// Stop a download, keeping the data downloaded so far so it can be
+    /// resumed later. Replies with false if there is no such download.
+    Pause(String, RpcReplyPort<bool>),
+    /// Stop a download and remove it, including any partially downloaded
+    /// data. Replies with false if there is no such download.
+    Delete(String, RpcReplyPort<bool>),
+    /// Get the IDs of the downloads that are currently downloading.
+    Running(RpcReplyPort<Vec<String>>),
+    /// Get all the downloads that haven't completed yet.
+    List(RpcReplyPort<Vec<Progress>>),
+    /// Get a download, if it exists and hasn't completed yet.
+    Get(String, RpcReplyPort<Option<Progress>>),
+    /// Sent by a downloader as it makes progress.
+    Progress(ActorId, ProgressUpdate),
+    /// Sent by a downloader as it extracts the file it downloaded.
+    Extracting(ActorId, ExtractProgress),
+    /// Add new downloads. If the duplicate policy is not set, the
+    /// coordinator's default policy is used.
+    Enqueue(
+        Owner,
+        Vec<BatchEntry>,
+        Option<DuplicatePolicy>,
+        RpcReplyPort<Vec<Enqueued>>,
+    ),
+    /// Replies right away, to check that the coordinator is still running.
+    Ping(RpcReplyPort<()>),
+    /// Use new settings. Running downloads keep going even if there are now
+    /// more than allowed, and keep the destinations they started with.
+    Reconfigure(DownloadSettings),
+    /// Sent now and then to check the free space on the disk.
+    CheckDisk,
+    /// Whether the queue is held because the disk is nearly full.
+    LowDisk(RpcReplyPort<Option<LowDisk>>),
 }
 
 #[derive(Debug)]
+pub struct ProgressUpdate {
+    pub progress: u64,
+    /// How much of the file has been synced to the disk.
+    pub verified: u64,
+    pub total: Option<u64>,
+    /// Bytes per second.
+    pub speed: f64,
+}
 
 /// An actor that downloads a file.
+#[derive(Debug)]
 pub struct Downloader {
-    pub url: String,
+    pub id: String,
+    /// The download as it was when the downloader started. The coordinator
+    /// picks the target file and start time before starting the downloader.
+    pub record: Progress,
     pub coordinator: ActorRef<Coordinator>,
-    pub store: DownloadProgressStore,
+    pub destinations: Arc<DestinationPolicy>,
+    pub client: Client,
+    pub hook: Option<PostHook>,
+    pub extract: ExtractSettings,
+    pub min_free_space: u64,
+    pub preallocate: Vec<String>,
+    /// How often to sync the file to the disk, to move the verified
+    /// watermark along.
+    pub sync_interval: Duration,
+    pub preserve_mtime: bool,
+    pub origin_xattr: bool,
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum DownloadError {
     #[error("Failed to download file, it was not found: {0}")]
     NotFound(String),
+    /// Trying again won't help, so these fail the download right away.
+    #[error("Failed to download file: {0}")]
+    Blocked(String),
+    /// The file was downloaded, but the post-completion hook failed and is
+    /// set to fail the download. This isn't retried either.
+    #[error("The post-completion hook {0}")]
+    Hook(HookResult),
+    /// The file was downloaded, but extracting it failed. Downloading it
+    /// again is unlikely to help, so this isn't retried either.
+    #[error("Failed to extract {0}: {1}")]
+    Extract(String, ExtractError),
+    /// The file doesn't fit on the disk. This pauses the download until the
+    /// user resumes it, rather than retrying it.
+    #[error(
+        "Not enough free space: the file is {}, and {} is free with {} to be kept free",
+        crate::human_bytes(*.size),
+        crate::human_bytes(*.free),
+        crate::human_bytes(*.min_free_space)
+    )]
+    NoSpace {
+        size: u64,
+        free: u64,
+        min_free_space: u64,
+    },
 }
 
 #[async_trait::async_trait]
@@ -87,7 +332,9 @@ impl Actor for Downloader {
     /// Downloader does not accept any messages, you start a download and let it finish.
     type Msg = ();
 
-    type State = ();
+    /// Once the download is complete, the details of the finished download.
+    /// The coordinator picks this up once the downloader stops.
+    type State = Option<HistoryEntry>;
     type Arguments = ();
 
     /// Open the file and get ready to write
@@ -96,40 +343,80 @@ impl Actor for Downloader {
         _myself: ActorRef<Self>,
         _: (),
     ) -> Result<Self::State, ActorProcessingErr> {
-        Ok(())
+        Ok(None)
     }
 
     /// Start the download and send progress updates to the coordinator.
     async fn post_start(
         &self,
         myself: ActorRef<Self>,
-        _state: &mut Self::State,
+        state: &mut Self::State,
     ) -> Result<(), ActorProcessingErr> {
-        let filename = self
-            .store
-            .get(&self.url)
-            .await?
-            .and_then(|v| v.target_file)
-            .unwrap_or_else(|| format!(".{}.tmp", Ulid::new().to_string()));
-        info!("Downloading {} to {}", self.url, &filename);
+        let record = &self.record;
+        let options = record.options.clone();
+        let started_at = record.started_at.unwrap_or_else(unix_now);
+        let filename = record
+            .target_file
+            .clone()
+            .unwrap_or_else(|| format!(".{}.tmp", Ulid::new()));
+        info!("Downloading {} to {}", record.url, &filename);
 
-        // If a file exists, resume from where it left off. We can't read the
+        // If a file exists, resume from where it left off. We can't use the
         // progress from the store because all of the file data might not have
-        // gotten persisted to the disk if there was a power outage or crash.
-        let resume_progress = fs::metadata(&filename).await.map(|v| v.len()).unwrap_or(0);
+        // gotten persisted to the disk if there was a power outage or crash,
+        // but the verified part was synced. The file may be shorter than that
+        // if it was deleted in the meantime.
+        let length = fs::metadata(&filename).await.map(|v| v.len()).unwrap_or(0);
+        let resume_progress = match record.verified {
+            Some(verified) => verified.min(length),
+            None => length,
+        };
 
-        let url = self.url.clone();
-        let client = Client::new();
-        let mut req_builder = client.get(&url);
+        let url = record.url.clone();
+        let parsed = Url::parse(&url)?;
+        self.destinations
+            .check_url(&parsed)
+            .map_err(|err| DownloadError::Blocked(err.to_string()))?;
+        let mut req_builder = self.client.get(parsed);
+        for (name, value) in &options.headers {
+            req_builder = req_builder.header(name, value);
+        }
         if resume_progress > 0 {
             req_builder = req_builder.header("Range", format!("bytes={}-", resume_progress));
         }
-        let req = req_builder.send().await?;
+        let req = req_builder.send().await.map_err(|err| {
+            if destination::is_blocked(&err) {
+                ActorProcessingErr::from(DownloadError::Blocked(err.to_string()))
+            } else {
+                err.into()
+            }
+        })?;
 
         if req.status() == reqwest::StatusCode::NOT_FOUND {
             return Err(DownloadError::NotFound(url).into());
         }
         let resuming = req.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+        let last_modified = origin::last_modified(req.headers());
+        // When resuming, this is only what is left to download.
+        if let Some(size) = req.content_length() {
+            // A preallocated file already has the space for some of the rest.
+            let allocated = if resuming {
+                length.saturating_sub(resume_progress)
+            } else {
+                0
+            };
+            self.check_free_space(Path::new(&filename), size.saturating_sub(allocated))?;
+            // The whole file has to fit where it is moved to at the end, if
+            // that is on another filesystem.
+            let target = final_path(record);
+            if !disk::same_filesystem(Path::new(&filename), &target) {
+                let already_downloaded = if resuming { resume_progress } else { 0 };
+                self.check_free_space(&target, size + already_downloaded)?;
+            }
+        }
+        // After any redirects.
+        let host_bytes = metrics::DOWNLOADED_BYTES_BY_HOST
+            .with_label_values(&[req.url().host_str().unwrap_or("")]);
         let mut file = OpenOptions::new()
             .write(true)
             .create(true)
@@ -137,13 +424,38 @@ impl Actor for Downloader {
             // we're downloading, or if the server doesn't support resuming,
             // then truncate the file to start from the beginning.
             .truncate(!resuming)
-            // If we are resuming, then we want to append to the end.
-            .append(resuming)
             .open(&filename)
             .await?;
+        if resuming {
+            // Anything past the verified part is downloaded again.
+            file.seek(SeekFrom::Start(resume_progress)).await?;
+        } else if let Some(size) = req.content_length() {
+            self.preallocate(&file, &filename, size)?;
+        }
+
+        // The hash covers the whole file, so if we are resuming we need to
+        // catch up on the part that is already downloaded.
+        let mut hasher = Sha256::new();
+        if resuming {
+            let mut existing = File::open(&filename).await?.take(resume_progress);
+            let mut buffer = vec![0u8; 64 * 1024];
+            loop {
+                let read = existing.read(&mut buffer).await?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..read]);
+            }
+        }
 
-        let total = req.content_length();
-        let mut progress: u64 = resume_progress;
+        // When resuming, the server only sends us the rest of the file.
+        let already_downloaded = if resuming { resume_progress } else { 0 };
+        let total = req
+            .content_length()
+            .map(|length| length + already_downloaded);
+        let mut progress: u64 = already_downloaded;
+        let mut verified: u64 = already_downloaded;
+        let mut last_sync = Instant::now();
 
         let mut last_update = Instant::now();
         let mut bytes_since_last_update = 0u64;
@@ -156,76 +468,465 @@ impl Actor for Downloader {
             let completed = chunk.len() as u64;
 
             file.write_all(&chunk).await?;
+            hasher.update(&chunk);
             progress += completed;
+            metrics::DOWNLOADED_BYTES.inc_by(completed);
+            host_bytes.inc_by(completed);
             bytes_since_last_update += completed;
 
             // Every second or so, we send out an update of how much we've
             // downloaded, and what our current speed estimate is.
             let time_since_last_update = Instant::now().duration_since(last_update).as_millis();
             if time_since_last_update > 1000 {
+                if last_sync.elapsed() >= self.sync_interval {
+                    file.sync_data().await?;
+                    verified = progress;
+                    last_sync = Instant::now();
+                }
                 download_speed_average.add(bytes_since_last_update, time_since_last_update as u64);
-                self.store
-                    .put(
-                        &url,
-                        &Progress {
-                            target_file: Some(filename.clone()),
-                            failed: false,
-                            url: url.clone(),
-                            total,
+                cast!(
+                    self.coordinator,
+                    CoordinatorMsg::Progress(
+                        myself.get_id(),
+                        ProgressUpdate {
                             progress,
+                            verified,
+                            total,
                             // bytes per millisecond to bytes per second
                             speed: download_speed_average.average() / 1000.0,
-                        },
+                        }
                     )
-                    .await?;
+                )?;
                 last_update = Instant::now();
                 bytes_since_last_update = 0;
             }
         }
 
-        // Make sure the data is written to disk before we call the download complete
+        // Make sure the data is written to disk before we call the download
+        // complete. A preallocated file may be longer than what the server
+        // sent, if it sent less than it said it would.
         file.flush().await?;
+        file.set_len(progress).await?;
         file.sync_all().await?;
         drop(file);
 
-        let final_filename = url_to_filename(&self.url);
-        info!("Putting download into {}", final_filename);
-        fs::rename(filename, final_filename).await?;
+        let final_filename = final_path(record);
+        if let Some(parent) = final_filename.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        info!("Putting download into {}", final_filename.display());
+        fs::rename(filename, &final_filename).await?;
+        self.mark_file(&final_filename, last_modified);
+
+        let completed_at = unix_now();
+        let duration = completed_at.saturating_sub(started_at);
+        let mut entry = HistoryEntry {
+            id: self.id.clone(),
+            url,
+            path: final_filename.to_string_lossy().to_string(),
+            size: progress,
+            duration,
+            speed: progress as f64 / duration.max(1) as f64,
+            sha256: format!("{:x}", hasher.finalize()),
+            completed_at,
+            options,
+            owner: record.owner.clone(),
+            hook: None,
+            extracted_to: None,
+        };
+
+        if let Some(folder) = self.extract(&myself, &final_filename).await? {
+            entry.extracted_to = Some(folder.to_string_lossy().to_string());
+        }
+
+        // The download keeps its place among the running downloads until
+        // the hook is done.
+        if let Some(hook) = &self.hook {
+            debug!("Running the post-completion hook for {}", entry.path);
+            let result = hook.run(&entry).await;
+            if !result.success() {
+                warn!("The post-completion hook for {} {}", entry.path, result);
+                if hook.fail_download {
+                    return Err(DownloadError::Hook(result).into());
+                }
+            }
+            entry.hook = Some(result);
+        }
+        *state = Some(entry);
 
         myself.stop(None);
         Ok(())
     }
 }
 
-static MAX_RETRIES: u64 = 24;
+impl Downloader {
+    /// Keep the modification time and origin of the file, as far as the
+    /// settings and the filesystem allow. Failing to only gets logged.
+    fn mark_file(&self, path: &Path, last_modified: Option<SystemTime>) {
+        if let (true, Some(time)) = (self.preserve_mtime, last_modified) {
+            if let Err(err) = origin::set_modified(path, time) {
+                warn!(
+                    "Failed to set the modification time of {}: {}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+        if self.origin_xattr {
+            let referrer = self
+                .record
+                .options
+                .headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("referer"))
+                .map(|(_, value)| value.as_str());
+            match origin::set_origin(path, &self.record.url, referrer) {
+                Ok(()) => {}
+                Err(err) if origin::is_unsupported(&err) => debug!(
+                    "Not recording the origin of {}, the filesystem has no extended attributes",
+                    path.display()
+                ),
+                Err(err) => warn!("Failed to record the origin of {}: {}", path.display(), err),
+            }
+        }
+    }
+
+    /// Preallocate the file if it is on one of the filesystems that is set
+    /// up for it. Running out of space fails the download, anything else only
+    /// skips preallocating.
+    fn preallocate(&self, file: &File, filename: &str, size: u64) -> Result<(), io::Error> {
+        let filesystem = match disk::filesystem(Path::new(filename)) {
+            Ok(Some(filesystem)) => filesystem,
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                debug!("Failed to check the filesystem of {}: {}", filename, err);
+                return Ok(());
+            }
+        };
+        if !self.preallocate.iter().any(|name| name == filesystem) {
+            return Ok(());
+        }
+        match disk::preallocate(file, size) {
+            Ok(()) => {
+                debug!("Preallocated {} bytes for {}", size, filename);
+                Ok(())
+            }
+            Err(err) if err.kind() == io::ErrorKind::StorageFull => Err(err),
+            Err(err) => {
+                warn!("Failed to preallocate {}: {}", filename, err);
+                Ok(())
+            }
+        }
+    }
+
+    /// Check that `size` more bytes fit on the disk the path is on, leaving the
+    /// minimum free space. If the free space can't be checked, the download
+    /// goes ahead.
+    fn check_free_space(&self, path: &Path, size: u64) -> Result<(), DownloadError> {
+        let free = match disk::free_space(path) {
+            Ok(free) => free,
+            Err(err) => {
+                debug!(
+                    "Failed to check the free space for {}: {}",
+                    path.display(),
+                    err
+                );
+                return Ok(());
+            }
+        };
+        if size.saturating_add(self.min_free_space) > free {
+            return Err(DownloadError::NoSpace {
+                size,
+                free,
+                min_free_space: self.min_free_space,
+            });
+        }
+        Ok(())
+    }
+
+    /// Extract the file if it is an archive that should be extracted, and
+    /// return the folder it was extracted into.
+    async fn extract(
+        &self,
+        myself: &ActorRef<Self>,
+        path: &Path,
+    ) -> Result<Option<PathBuf>, ActorProcessingErr> {
+        let settings = &self.extract;
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let detected = ArchiveKind::detect(&name);
+        let wanted = match self.record.options.extract {
+            Some(extract) => extract,
+            None => detected.is_some_and(|(kind, _)| settings.auto.contains(&kind)),
+        };
+        if !wanted {
+            return Ok(None);
+        }
+        let Some((kind, stem)) = detected else {
+            warn!(
+                "Not extracting {}, it isn't an archive we can extract",
+                name
+            );
+            return Ok(None);
+        };
+        info!("Extracting {}", path.display());
+
+        let archive = path.to_path_buf();
+        let stem = stem.to_string();
+        let coordinator = self.coordinator.clone();
+        let id = myself.get_id();
+        let task_settings = settings.clone();
+        let folder = tokio::task::spawn_blocking(move || {
+            let mut last_update: Option<Instant> = None;
+            extract::extract(&archive, kind, &stem, &task_settings, |progress| {
+                // Every second or so, like the download progress.
+                let due = last_update.is_none_or(|last| last.elapsed().as_millis() > 1000);
+                if due || progress.done == progress.total {
+                    let _ = cast!(coordinator, CoordinatorMsg::Extracting(id, progress));
+                    last_update = Some(Instant::now());
+                }
+            })
+        })
+        .await?
+        .map_err(|err| DownloadError::Extract(name.clone(), err))?;
+        info!("Extracted {} into {}", path.display(), folder.display());
+
+        if settings.delete_archive {
+            fs::remove_file(path).await?;
+        }
+        Ok(Some(folder))
+    }
+}
 
 impl Coordinator {
     async fn start_download(
         &self,
         myself: &ActorRef<Self>,
         state: &mut CoordinatorState,
-        url: &str,
+        id: &str,
         existing_retries: u64,
     ) -> Result<(), ActorProcessingErr> {
+        let Some(record) = state.downloads.get_mut(id) else {
+            warn!("Download {} was removed before it could start", id);
+            return Ok(());
+        };
+        record.extracting = None;
+        // Pick the file to download into up front, so that a retry resumes
+        // the same file even if the downloader never reported any progress.
+        if record.target_file.is_none() || record.started_at.is_none() {
+            record
+                .target_file
+                .get_or_insert_with(|| format!(".{}.tmp", Ulid::new()));
+            record.started_at.get_or_insert_with(unix_now);
+            self.store.put(id, record).await?;
+        }
+        let record = record.clone();
         let downloader = Downloader {
-            url: url.to_string(),
+            id: id.to_string(),
+            record: record.clone(),
             coordinator: myself.clone(), // cloning the reference, not the actor
-            store: self.store.clone(),
+            destinations: state.settings.destinations.clone(),
+            client: state.client.clone(),
+            hook: state.settings.hook.clone(),
+            extract: state.settings.extract.clone(),
+            min_free_space: state.settings.min_free_space,
+            preallocate: state.settings.preallocate.clone(),
+            sync_interval: state.settings.checkpoint_interval,
+            preserve_mtime: state.settings.preserve_mtime,
+            origin_xattr: state.settings.origin_xattr,
         };
         let (actor, handle) = Actor::spawn_linked(None, downloader, (), myself.get_cell()).await?;
+        self.send_state(&record, DownloadEvent::Started);
 
         state.children.insert(
             actor.get_id(),
             DownloaderRef {
                 id: actor.get_id(),
-                url: url.to_string(),
+                download_id: id.to_string(),
                 actor,
                 handle,
                 retries: existing_retries + 1,
+                last_checkpoint: Instant::now(),
             },
         );
         Ok(())
     }
+
+    fn send_state(&self, record: &Progress, state: DownloadEvent) {
+        self.events.send(
+            &record.owner,
+            Event::State {
+                id: record.id.clone(),
+                state,
+            },
+        );
+    }
+
+    /// Start downloads from the queue until we are downloading as many files
+    /// as we are allowed to at once.
+    async fn start_queued(
+        &self,
+        myself: &ActorRef<Self>,
+        state: &mut CoordinatorState,
+    ) -> Result<(), ActorProcessingErr> {
+        if state.low_disk.is_some() {
+            state.update_metrics();
+            return Ok(());
+        }
+        while state.children.len() < state.settings.concurrent_downloads {
+            let Some(id) = state.queue.pop_front() else {
+                break;
+            };
+            self.start_download(myself, state, &id, 0).await?;
+        }
+        state.update_metrics();
+        Ok(())
+    }
+
+    /// Stop the downloader for this download if it is running, and take the
+    /// download out of the queue.
+    ///
+    /// Downloaders don't process any messages until they are done, so we
+    /// can't ask them to stop. The task running it is aborted instead.
+    fn stop_download(&self, myself: &ActorRef<Self>, state: &mut CoordinatorState, id: &str) {
+        state.queue.retain(|queued| queued != id);
+        let running = state
+            .children
+            .values()
+            .find(|child| child.download_id == id)
+            .map(|child| child.id);
+        if let Some(child) = running.and_then(|child| state.children.remove(&child)) {
+            child.actor.unlink(myself.get_cell());
+            child.handle.abort();
+        }
+    }
+
+    /// Stop the running downloads and put them back at the front of the
+    /// queue, to continue once there is space on the disk again.
+    async fn hold_queue(
+        &self,
+        myself: &ActorRef<Self>,
+        state: &mut CoordinatorState,
+        low_disk: LowDisk,
+    ) -> Result<(), ActorProcessingErr> {
+        if state.low_disk.replace(low_disk).is_none() {
+            warn!(
+                "Only {} bytes are free on the disk, holding the downloads until there are more than {}",
+                low_disk.free, low_disk.min_free_space
+            );
+        }
+        let running: Vec<String> = state
+            .children
+            .values()
+            .map(|child| child.download_id.clone())
+            .collect();
+        for id in running.into_iter().rev() {
+            self.stop_download(myself, state, &id);
+            state.queue.push_front(id.clone());
+            if let Some(record) = state.downloads.get_mut(&id) {
+                record.speed = 0f64;
+                self.store.put(&id, record).await?;
+                self.send_state(record, DownloadEvent::Queued);
+            }
+        }
+        state.update_metrics();
+        Ok(())
+    }
+
+    /// Hold the queue if the disk is nearly full, and let it continue once
+    /// there is space again.
+    async fn check_disk(
+        &self,
+        myself: &ActorRef<Self>,
+        state: &mut CoordinatorState,
+    ) -> Result<(), ActorProcessingErr> {
+        let min_free_space = state.settings.min_free_space;
+        let free = match disk::free_space(Path::new(disk::TEMP_DIR)) {
+            Ok(free) => free,
+            Err(err) => {
+                debug!("Failed to check the free space: {}", err);
+                return Ok(());
+            }
+        };
+        let low = match state.low_disk {
+            // Continue once there is some room again, so a disk that is
+            // exactly full doesn't restart the downloads right away.
+            Some(_) => free <= min_free_space,
+            None => free < min_free_space,
+        };
+        if low {
+            let low_disk = LowDisk {
+                free,
+                min_free_space,
+            };
+            self.hold_queue(myself, state, low_disk).await?;
+        } else if state.low_disk.take().is_some() {
+            info!(
+                "{} bytes are free on the disk, continuing the downloads",
+                free
+            );
+        }
+        Ok(())
+    }
+
+    /// Record new downloads and queue them up, checking the duplicate policy
+    /// for URLs that are already being downloaded.
+    async fn enqueue(
+        &self,
+        state: &mut CoordinatorState,
+        owner: Owner,
+        entries: Vec<BatchEntry>,
+        policy: DuplicatePolicy,
+    ) -> Result<Vec<Enqueued>, ActorProcessingErr> {
+        // Find the owner's downloads that are still going, by URL. Other
+        // users downloading the same URL get their own copy.
+        let mut active: HashMap<String, String> = HashMap::new();
+        for (id, record) in &state.downloads {
+            if record.owner == owner.username && state.is_active(id) {
+                active.insert(record.url.clone(), id.clone());
+            }
+        }
+
+        let mut results = Vec::with_capacity(entries.len());
+        for entry in entries {
+            match (active.get(&entry.url), policy) {
+                (Some(id), DuplicatePolicy::Reject) => {
+                    results.push(Enqueued::Duplicate(id.clone()));
+                    continue;
+                }
+                (Some(id), DuplicatePolicy::Attach) => {
+                    results.push(Enqueued::Attached(id.clone()));
+                    continue;
+                }
+                _ => {}
+            }
+
+            let id = Ulid::new().to_string();
+            let record = Progress {
+                options: entry.options,
+                owner: owner.username.clone(),
+                base_dir: owner.base_dir.clone(),
+                ..Progress::default_with(id.clone(), entry.url.clone())
+            };
+            self.store.put(&id, &record).await?;
+            self.events.send(
+                &record.owner,
+                Event::Added {
+                    id: id.clone(),
+                    url: entry.url.clone(),
+                },
+            );
+            self.send_state(&record, DownloadEvent::Queued);
+            self.webhooks.send(WebhookEvent::Added, &record);
+            state.downloads.insert(id.clone(), record);
+            state.queue.push_back(id.clone());
+            active.insert(entry.url, id.clone());
+            results.push(Enqueued::Accepted(id));
+        }
+        Ok(results)
+    }
 }
 
 #[async_trait::async_trait]
@@ -241,8 +942,20 @@ impl Actor for Coordinator {
         _: (),
     ) -> Result<Self::State, ActorProcessingErr> {
         debug!("Starting coordinator");
+        let downloads = self
+            .store
+            .scan()
+            .await?
+            .into_iter()
+            .map(|(id, record)| (id.clone(), Progress { id, ..record }))
+            .collect();
         Ok(CoordinatorState {
             children: HashMap::new(),
+            queue: VecDeque::new(),
+            downloads,
+            settings: self.settings.clone(),
+            client: self.settings.destinations.client()?,
+            low_disk: None,
         })
     }
 
@@ -253,15 +966,14 @@ impl Actor for Coordinator {
         myself: ActorRef<Self>,
         state: &mut Self::State,
     ) -> Result<(), ActorProcessingErr> {
-        let files = self.store.scan().await.unwrap();
-        let files = files.map(|x| x.unwrap().1);
-        let files = files.filter(|x| future::ready(!x.failed));
-        let files = files.collect::<Vec<_>>().await;
-
-        for file in files {
-            self.start_download(&myself, state, &file.url, 0).await?;
+        for (id, file) in &state.downloads {
+            if !file.failed && !file.paused {
+                state.queue.push_back(id.clone());
+            }
         }
-        Ok(())
+        myself.send_interval(DISK_CHECK_INTERVAL, || CoordinatorMsg::CheckDisk);
+        self.check_disk(&myself, state).await?;
+        self.start_queued(&myself, state).await
     }
 
     async fn handle(
@@ -271,13 +983,156 @@ impl Actor for Coordinator {
         state: &mut Self::State,
     ) -> Result<(), ActorProcessingErr> {
         match message {
-            CoordinatorMsg::StartDownload(download) => {
-                self.start_download(&myself, state, &download.url, 0)
+            CoordinatorMsg::Retry(id, reply) => {
+                let active = state.is_active(&id);
+                let record = state.downloads.get_mut(&id);
+                let found = record.is_some();
+                if let Some(record) = record {
+                    if !active {
+                        let retried = record.failed;
+                        record.failed = false;
+                        record.paused = false;
+                        record.error = None;
+                        record.hook = None;
+                        self.store.put(&id, record).await?;
+                        self.send_state(record, DownloadEvent::Queued);
+                        if retried {
+                            self.webhooks.send(WebhookEvent::Retried, record);
+                        }
+                        state.queue.push_back(id);
+                    }
+                }
+                let _ = reply.send(found);
+            }
+            CoordinatorMsg::Pause(id, reply) => {
+                let found = state.downloads.contains_key(&id);
+                if found {
+                    self.stop_download(&myself, state, &id);
+                    if let Some(record) = state.downloads.get_mut(&id) {
+                        record.paused = true;
+                        record.speed = 0f64;
+                        record.extracting = None;
+                        self.store.put(&id, record).await?;
+                        self.send_state(record, DownloadEvent::Paused);
+                    }
+                }
+                let _ = reply.send(found);
+            }
+            CoordinatorMsg::Delete(id, reply) => {
+                let record = state.downloads.remove(&id);
+                let found = record.is_some();
+                if let Some(record) = record {
+                    self.stop_download(&myself, state, &id);
+                    self.store.delete(&id).await?;
+                    self.events
+                        .send(&record.owner, Event::Removed { id: id.clone() });
+                    if let Some(target_file) = record.target_file {
+                        match fs::remove_file(&target_file).await {
+                            Ok(()) => {}
+                            // The download may not have started yet.
+                            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                            Err(err) => warn!("Failed to remove {}: {:?}", target_file, err),
+                        }
+                    }
+                }
+                let _ = reply.send(found);
+            }
+            CoordinatorMsg::Running(reply) => {
+                let running = state
+                    .children
+                    .values()
+                    .map(|child| child.download_id.clone())
+                    .collect();
+                let _ = reply.send(running);
+            }
+            CoordinatorMsg::List(reply) => {
+                let _ = reply.send(state.downloads.values().cloned().collect());
+            }
+            CoordinatorMsg::Get(id, reply) => {
+                let _ = reply.send(state.downloads.get(&id).cloned());
+            }
+            CoordinatorMsg::Progress(downloader, update) => {
+                // Downloaders that were paused or deleted may have left
+                // updates behind in the mailbox, which we ignore.
+                let Some(child) = state.children.get_mut(&downloader) else {
+                    return Ok(());
+                };
+                let Some(record) = state.downloads.get_mut(&child.download_id) else {
+                    return Ok(());
+                };
+                record.progress = update.progress;
+                record.verified = Some(update.verified);
+                record.total = update.total;
+                record.speed = update.speed;
+                self.events.send(
+                    &record.owner,
+                    Event::Progress {
+                        id: record.id.clone(),
+                        progress: update.progress,
+                        total: update.total,
+                        speed: update.speed,
+                    },
+                );
+                if child.last_checkpoint.elapsed() >= state.settings.checkpoint_interval {
+                    self.store.put(&record.id, record).await?;
+                    child.last_checkpoint = Instant::now();
+                }
+                state.update_metrics();
+                return Ok(());
+            }
+            CoordinatorMsg::Extracting(downloader, update) => {
+                let Some(child) = state.children.get(&downloader) else {
+                    return Ok(());
+                };
+                let Some(record) = state.downloads.get_mut(&child.download_id) else {
+                    return Ok(());
+                };
+                let started = record.extracting.is_none();
+                record.extracting = Some(update);
+                record.speed = 0f64;
+                if started {
+                    self.send_state(record, DownloadEvent::Extracting);
+                }
+                self.events.send(
+                    &record.owner,
+                    Event::Extracting {
+                        id: record.id.clone(),
+                        done: update.done,
+                        total: update.total,
+                    },
+                );
+                return Ok(());
+            }
+            CoordinatorMsg::Enqueue(owner, entries, policy, reply) => {
+                let results = self
+                    .enqueue(
+                        state,
+                        owner,
+                        entries,
+                        policy.unwrap_or(state.settings.duplicate_policy),
+                    )
                     .await?;
+                if !reply.is_closed() {
+                    let _ = reply.send(results);
+                }
+            }
+            CoordinatorMsg::Ping(reply) => {
+                let _ = reply.send(());
+            }
+            CoordinatorMsg::Reconfigure(settings) => match settings.destinations.client() {
+                Ok(client) => {
+                    state.client = client;
+                    state.settings = settings;
+                }
+                Err(err) => error!("Failed to apply the new download settings: {}", err),
+            },
+            CoordinatorMsg::CheckDisk => self.check_disk(&myself, state).await?,
+            CoordinatorMsg::LowDisk(reply) => {
+                let _ = reply.send(state.low_disk);
             }
         }
 
-        Ok(())
+        self.start_queued(&myself, state).await
     }
 
     async fn handle_supervisor_evt(
@@ -287,49 +1142,118 @@ impl Actor for Coordinator {
         state: &mut Self::State,
     ) -> Result<(), ActorProcessingErr> {
         match message {
-            SupervisionEvent::ActorTerminated(child, _state, _reason) => {
-                let url = state.children.get(&child.get_id()).unwrap().url.clone();
-                info!("Download finished: {:?}", url);
-                self.store.delete(url).await?;
-                state.children.remove(&child.get_id());
-                Ok(())
+            SupervisionEvent::ActorTerminated(child, child_state, _reason) => {
+                // Paused or deleted while finishing up, which already took
+                // care of the download.
+                let Some(child) = state.children.remove(&child.get_id()) else {
+                    return self.start_queued(&myself, state).await;
+                };
+                info!("Download finished: {:?}", child.download_id);
+                let finished = child_state
+                    .and_then(|mut child_state| child_state.take::<Option<HistoryEntry>>().ok())
+                    .flatten();
+                let Some(record) = state.downloads.remove(&child.download_id) else {
+                    // Deleted while finishing up.
+                    return self.start_queued(&myself, state).await;
+                };
+                if let Some(entry) = finished {
+                    metrics::COMPLETION_DURATION.observe(entry.duration as f64);
+                    self.history.put(&entry.id, &entry).await?;
+                    self.send_state(&record, DownloadEvent::Completed);
+                    self.webhooks.send(WebhookEvent::Completed, &record);
+                    self.notifier.completed(&entry);
+                    self.push.completed(&entry);
+                }
+                self.store.delete(&child.download_id).await?;
+                self.events.send(
+                    &record.owner,
+                    Event::Removed {
+                        id: child.download_id,
+                    },
+                );
             }
             SupervisionEvent::ActorPanicked(child, err) => {
-                let child = state.children.get(&child.get_id()).unwrap();
-                let url = child.url.clone();
-                let child_id = child.id.clone();
-                drop(child);
-
-                if child.retries > MAX_RETRIES {
-                    error!("Download failed, giving up: {:?}", url);
-
-                    let last_state = self
-                        .store
-                        .get(&url)
-                        .await?
-                        .unwrap_or_else(|| Progress::default_with(url.clone()));
-                    // Update the state to indicate that the download failed
-                    self.store
-                        .put(
-                            &url,
-                            &Progress {
-                                failed: true,
-                                ..last_state
-                            },
-                        )
-                        .await?;
+                // Paused or deleted while failing.
+                let Some(child) = state.children.remove(&child.get_id()) else {
+                    return self.start_queued(&myself, state).await;
+                };
+                let id = child.download_id;
 
-                    state.children.remove(&child_id);
-                    return Ok(());
+                let download_error = err.downcast_ref::<DownloadError>();
+                let blocked = matches!(
+                    download_error,
+                    Some(
+                        DownloadError::Blocked(_)
+                            | DownloadError::Hook(_)
+                            | DownloadError::Extract(..)
+                    )
+                );
+                if let Some(record) = state.downloads.get_mut(&id) {
+                    record.extracting = None;
+                }
+                if let Some(DownloadError::NoSpace { .. }) = download_error {
+                    warn!("Pausing download {}: {}", id, err);
+                    if let Some(record) = state.downloads.get_mut(&id) {
+                        record.paused = true;
+                        record.speed = 0f64;
+                        record.error = Some(err.to_string());
+                        self.store.put(&id, record).await?;
+                        self.send_state(record, DownloadEvent::Paused);
+                    }
+                    return self.start_queued(&myself, state).await;
+                }
+                let disk_full = err
+                    .downcast_ref::<std::io::Error>()
+                    .is_some_and(|err| err.kind() == std::io::ErrorKind::StorageFull);
+                if disk_full {
+                    // The other downloads are about to fail the same way.
+                    // This doesn't count as a retry.
+                    warn!("The disk is full, download {} will continue later", id);
+                    if let Some(record) = state.downloads.get_mut(&id) {
+                        record.speed = 0f64;
+                        self.send_state(record, DownloadEvent::Queued);
+                    }
+                    state.queue.push_front(id);
+                    let low_disk = LowDisk {
+                        free: disk::free_space(Path::new(disk::TEMP_DIR)).unwrap_or(0),
+                        min_free_space: state.settings.min_free_space,
+                    };
+                    return self.hold_queue(&myself, state, low_disk).await;
                 }
+                if child.retries > state.settings.max_retries || blocked {
+                    error!("Download failed, giving up: {:?}, {:?}", id, err);
 
-                warn!("Download failed, restarting: {:?}, {:?}", &url, err);
+                    if let Some(record) = state.downloads.get_mut(&id) {
+                        // Update the state to indicate that the download failed
+                        record.failed = true;
+                        record.speed = 0f64;
+                        record.error = Some(err.to_string());
+                        if let Some(DownloadError::Hook(result)) = download_error {
+                            record.hook = Some(result.clone());
+                        }
+                        self.store.put(&id, record).await?;
+                        self.send_state(record, DownloadEvent::Failed);
+                        self.webhooks.send(WebhookEvent::Failed, record);
+                        self.notifier.failed(record);
+                        self.push.failed(record);
+                    }
+                } else {
+                    warn!("Download failed, restarting: {:?}, {:?}", &id, err);
+                    metrics::RETRIES
+                        .with_label_values(&[metrics::error_class(&err)])
+                        .inc();
+                    if let Some(record) = state.downloads.get(&id) {
+                        self.webhooks.send(WebhookEvent::Retried, record);
+                    }
 
-                self.start_download(&myself, state, &url, child.retries)
-                    .await?;
-                Ok(())
+                    self.start_download(&myself, state, &id, child.retries)
+                        .await?;
+                    return Ok(());
+                }
             }
-            _ => Ok(()),
+            _ => return Ok(()),
         }
+
+        self.start_queued(&myself, state).await
     }
 }
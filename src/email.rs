@@ -0,0 +1,284 @@
+//! Email notifications, for the people who don't keep an eye on the web UI.
+//!
+//! An email can go out as soon as a download completes or fails, and a digest
+//! of the last day once a day. The digest lists the downloads that completed
+//! in the day before it, along with the ones that have failed and haven't been
+//! restarted. The messages are plain text, from the `email_*.txt` templates.
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use askama::Template;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use ractor::{call, ActorRef};
+use tracing::{debug, info, warn};
+
+use crate::{
+    download_actor::{Coordinator, CoordinatorMsg},
+    store::{unix_now, HistoryEntry, HistoryStore, Progress},
+    HistoryDisplay, ProgressDisplay,
+};
+
+/// How long to wait for the mail server.
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// How often to check whether it's time for the digest.
+const DIGEST_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const DAY: u64 = 24 * 60 * 60;
+
+/// How to secure the connection to the mail server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtpTls {
+    /// Connect in plain text and upgrade the connection, on port 587.
+    StartTls,
+    /// Connect with TLS, on port 465.
+    Tls,
+    /// Don't encrypt the connection at all, on port 25. Only for mail servers
+    /// on the same machine or network.
+    None,
+}
+
+impl std::str::FromStr for SmtpTls {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "starttls" => Ok(Self::StartTls),
+            "tls" => Ok(Self::Tls),
+            "none" => Ok(Self::None),
+            _ => Err(format!(
+                "Unknown TLS mode {}, expected starttls, tls or none",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct EmailSettings {
+    pub server: String,
+    /// The default port for the TLS mode if not set.
+    pub port: Option<u16>,
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: Mailbox,
+    pub to: Vec<Mailbox>,
+    pub on_completed: bool,
+    pub on_failed: bool,
+    /// When to send the digest, as seconds after midnight UTC.
+    pub digest_at: Option<u64>,
+}
+
+impl std::fmt::Debug for EmailSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EmailSettings")
+            .field("server", &self.server)
+            .field("port", &self.port)
+            .field("tls", &self.tls)
+            .field("username", &self.username)
+            .field("from", &self.from.to_string())
+            .field("to", &self.to.len())
+            .field("on_completed", &self.on_completed)
+            .field("on_failed", &self.on_failed)
+            .field("digest_at", &self.digest_at)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Parse a time of day like `08:30` into seconds after midnight.
+pub fn parse_time_of_day(time: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid time {}, expected something like 08:30", time);
+    let (hours, minutes) = time.split_once(':').ok_or_else(invalid)?;
+    let hours: u64 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u64 = minutes.parse().map_err(|_| invalid())?;
+    if hours >= 24 || minutes >= 60 {
+        return Err(invalid());
+    }
+    Ok(hours * 60 * 60 + minutes * 60)
+}
+
+#[derive(Template)]
+#[template(path = "email_completed.txt")]
+struct CompletedEmail {
+    download: HistoryDisplay,
+}
+
+#[derive(Template)]
+#[template(path = "email_failed.txt")]
+struct FailedEmail {
+    download: ProgressDisplay,
+}
+
+#[derive(Template)]
+#[template(path = "email_digest.txt")]
+struct DigestEmail {
+    completed: Vec<HistoryDisplay>,
+    failed: Vec<ProgressDisplay>,
+}
+
+/// Sends the emails in the background. Cloning it is cheap.
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    settings: Arc<RwLock<Option<EmailSettings>>>,
+}
+
+impl EmailSettings {
+    async fn send(&self, subject: &str, body: String) -> Result<(), String> {
+        let mut builder = Message::builder()
+            .from(self.from.clone())
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN);
+        for to in &self.to {
+            builder = builder.to(to.clone());
+        }
+        let message = builder.body(body).map_err(|err| err.to_string())?;
+
+        let mut transport = match self.tls {
+            SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&self.server)
+                .map_err(|err| err.to_string())?,
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&self.server)
+                .map_err(|err| err.to_string())?,
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.server),
+        }
+        .timeout(Some(TIMEOUT));
+        if let Some(port) = self.port {
+            transport = transport.port(port);
+        }
+        if let Some(username) = &self.username {
+            transport = transport.credentials(Credentials::new(
+                username.clone(),
+                self.password.clone().unwrap_or_default(),
+            ));
+        }
+        transport
+            .build()
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+}
+
+impl Notifier {
+    pub fn new(settings: Option<EmailSettings>) -> Self {
+        Notifier {
+            settings: Arc::new(RwLock::new(settings)),
+        }
+    }
+
+    /// Replace the settings, when the configuration is reloaded.
+    pub fn set(&self, settings: Option<EmailSettings>) {
+        *self.settings.write().unwrap() = settings;
+    }
+
+    fn settings(&self) -> Option<EmailSettings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Send the email in the background. Failures are only logged.
+    fn send_later(&self, settings: EmailSettings, subject: String, body: String) {
+        tokio::spawn(async move {
+            match settings.send(&subject, body).await {
+                Ok(()) => debug!("Sent the email \"{}\"", subject),
+                Err(err) => warn!("Failed to send the email \"{}\": {}", subject, err),
+            }
+        });
+    }
+
+    pub fn completed(&self, entry: &HistoryEntry) {
+        let Some(settings) = self.settings().filter(|settings| settings.on_completed) else {
+            return;
+        };
+        let download = HistoryDisplay::from(entry.clone());
+        let subject = format!("Downloaded {}", download.path);
+        match (CompletedEmail { download }).render() {
+            Ok(body) => self.send_later(settings, subject, body),
+            Err(err) => warn!("Failed to write the email \"{}\": {}", subject, err),
+        }
+    }
+
+    pub fn failed(&self, record: &Progress) {
+        let Some(settings) = self.settings().filter(|settings| settings.on_failed) else {
+            return;
+        };
+        let download = ProgressDisplay::from(record.clone());
+        let subject = format!("Failed to download {}", download.name);
+        match (FailedEmail { download }).render() {
+            Ok(body) => self.send_later(settings, subject, body),
+            Err(err) => warn!("Failed to write the email \"{}\": {}", subject, err),
+        }
+    }
+
+    /// Send the digest every day, at the time in the current settings.
+    pub fn spawn_digest(&self, history: HistoryStore, coordinator: ActorRef<Coordinator>) {
+        let notifier = self.clone();
+        tokio::spawn(async move {
+            let mut last_check = unix_now();
+            loop {
+                tokio::time::sleep(DIGEST_CHECK_INTERVAL).await;
+                let now = unix_now();
+                let due = notifier.settings().filter(|settings| {
+                    settings.digest_at.is_some_and(|at| {
+                        // The last time of day it was time for the digest.
+                        let last_due = now - (now % DAY + DAY - at) % DAY;
+                        last_check < last_due && last_due <= now
+                    })
+                });
+                last_check = now;
+                if let Some(settings) = due {
+                    if let Err(err) = digest(&settings, &history, &coordinator, now).await {
+                        warn!("Failed to send the daily digest: {}", err);
+                    }
+                }
+            }
+        });
+    }
+}
+
+async fn digest(
+    settings: &EmailSettings,
+    history: &HistoryStore,
+    coordinator: &ActorRef<Coordinator>,
+    now: u64,
+) -> Result<(), String> {
+    let since = now.saturating_sub(DAY);
+    let mut completed: Vec<HistoryEntry> = history
+        .scan()
+        .await
+        .map_err(|err| err.to_string())?
+        .into_iter()
+        .map(|(_, entry)| entry)
+        .filter(|entry| entry.completed_at > since)
+        .collect();
+    completed.sort_by_key(|entry| entry.completed_at);
+    let failed: Vec<ProgressDisplay> = call!(coordinator, CoordinatorMsg::List)
+        .map_err(|err| err.to_string())?
+        .into_iter()
+        .filter(|download| download.failed)
+        .map(ProgressDisplay::from)
+        .collect();
+    if completed.is_empty() && failed.is_empty() {
+        debug!("Nothing to put in the daily digest");
+        return Ok(());
+    }
+
+    let subject = format!(
+        "Daily digest: {} completed, {} failed",
+        completed.len(),
+        failed.len()
+    );
+    let body = DigestEmail {
+        completed: completed.into_iter().map(HistoryDisplay::from).collect(),
+        failed,
+    }
+    .render()
+    .map_err(|err| err.to_string())?;
+    settings.send(&subject, body).await?;
+    info!("Sent the daily digest");
+    Ok(())
+}
@@ -0,0 +1,143 @@
+//! Live updates about downloads, pushed to browsers as Server-Sent Events.
+//!
+//! The coordinator and downloaders publish events to a broadcast channel, and
+//! each client connected to `/events` gets its own receiver. Clients that fall
+//! behind miss some events rather than holding everyone else up.
+use std::time::Duration;
+
+use actix_web::{
+    get,
+    web::{Bytes, Data, ReqData},
+    HttpResponse, Responder,
+};
+use futures::{stream, StreamExt};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::debug;
+
+use crate::users::CurrentUser;
+
+/// How many events are buffered for each client before it starts missing
+/// them.
+const CAPACITY: usize = 256;
+
+/// How often to send a comment to idle clients, so proxies don't close the
+/// connection.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Published>,
+}
+
+/// An event, along with who it concerns.
+#[derive(Debug, Clone)]
+pub struct Published {
+    /// The user who owns the download the event is about.
+    pub owner: String,
+    pub event: Event,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        EventBus { sender }
+    }
+
+    /// Publish an event to everyone listening. Events are dropped if nobody is.
+    pub fn send(&self, owner: &str, event: Event) {
+        let _ = self.sender.send(Published {
+            owner: owner.to_string(),
+            event,
+        });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Published> {
+        self.sender.subscribe()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloadEvent {
+    /// Waiting for other downloads to finish.
+    Queued,
+    /// A downloader picked up the download, including after a retry.
+    Started,
+    Paused,
+    /// The download is done, and the archive is being extracted.
+    Extracting,
+    Completed,
+    /// Gave up after too many retries.
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Event {
+    /// A new download was added.
+    Added { id: String, url: String },
+    /// A download was deleted, or completed and moved to the history.
+    Removed { id: String },
+    /// A download changed state.
+    State { id: String, state: DownloadEvent },
+    /// How far along a download is, sent about once a second while it runs.
+    Progress {
+        id: String,
+        progress: u64,
+        total: Option<u64>,
+        /// Bytes per second.
+        speed: f64,
+    },
+    /// How much of the archive has been extracted, in bytes of the archive.
+    Extracting { id: String, done: u64, total: u64 },
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::Added { .. } => "added",
+            Event::Removed { .. } => "removed",
+            Event::State { .. } => "state",
+            Event::Progress { .. } => "progress",
+            Event::Extracting { .. } => "extracting",
+        }
+    }
+
+    /// Format the event for an event stream. The data is the event as JSON.
+    fn to_sse(&self) -> Bytes {
+        let data = serde_json::to_string(self).unwrap_or_default();
+        Bytes::from(format!("event: {}\ndata: {}\n\n", self.name(), data))
+    }
+}
+
+#[get("/events")]
+#[tracing::instrument(level = "debug", skip(bus))]
+async fn events(bus: Data<EventBus>, user: ReqData<CurrentUser>) -> impl Responder {
+    let user = user.into_inner();
+    let receiver = bus.subscribe();
+    let events = stream::unfold((receiver, user), |(mut receiver, user)| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(published) if user.can_see(&published.owner) => {
+                    return Some((published.event.to_sse(), (receiver, user)));
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    debug!("Event stream client missed {} events", missed);
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    let keep_alive = stream::unfold((), |_| async {
+        tokio::time::sleep(KEEP_ALIVE).await;
+        Some((Bytes::from_static(b": keep-alive\n\n"), ()))
+    });
+    let body = stream::select(events, keep_alive).map(Ok::<_, actix_web::Error>);
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(body)
+}
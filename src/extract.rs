@@ -0,0 +1,362 @@
+//! Unpacking downloaded archives, for downloads that are only wanted for what
+//! is inside them.
+//!
+//! Zip files and tarballs, compressed with gzip, xz or not at all, are
+//! extracted into a folder named after the archive once the download is in
+//! its final place. Archives come from the internet, so nothing in them may
+//! end up outside that folder: entries with absolute paths or `..` in them
+//! fail the extraction, and links are skipped. To stop archives that unpack
+//! into far more than they look like, extraction fails once it has written
+//! more than `max_ratio` times the size of the archive, or more than
+//! `max_files` files.
+//!
+//! Everything is extracted into a hidden folder first, and moved into place
+//! once it is done, so a failed extraction doesn't leave half of the files
+//! behind.
+use std::{
+    fmt::Display,
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use ulid::Ulid;
+use xz2::read::XzDecoder;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+    TarXz,
+}
+
+/// The file name endings of each kind of archive, longest first.
+const EXTENSIONS: [(&str, ArchiveKind); 6] = [
+    (".tar.gz", ArchiveKind::TarGz),
+    (".tar.xz", ArchiveKind::TarXz),
+    (".tgz", ArchiveKind::TarGz),
+    (".txz", ArchiveKind::TarXz),
+    (".tar", ArchiveKind::Tar),
+    (".zip", ArchiveKind::Zip),
+];
+
+impl ArchiveKind {
+    /// Tell the kind of archive from its file name. Returns the name without
+    /// the extension too, which is what the folder it is extracted into is
+    /// called.
+    pub fn detect(name: &str) -> Option<(ArchiveKind, &str)> {
+        let lowercase = name.to_lowercase();
+        EXTENSIONS.iter().find_map(|(extension, kind)| {
+            let stem = &name[..name.len().checked_sub(extension.len())?];
+            (lowercase.ends_with(extension) && !stem.is_empty()).then_some((*kind, stem))
+        })
+    }
+}
+
+impl FromStr for ArchiveKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zip" => Ok(Self::Zip),
+            "tar" => Ok(Self::Tar),
+            "tar.gz" => Ok(Self::TarGz),
+            "tar.xz" => Ok(Self::TarXz),
+            _ => Err(format!(
+                "Unknown archive type {}, expected zip, tar, tar.gz or tar.xz",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for ArchiveKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Zip => "zip",
+            Self::Tar => "tar",
+            Self::TarGz => "tar.gz",
+            Self::TarXz => "tar.xz",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtractSettings {
+    /// Archives of these kinds are extracted, unless the download says
+    /// otherwise.
+    pub auto: Vec<ArchiveKind>,
+    /// Delete the archive once it is extracted.
+    pub delete_archive: bool,
+    /// How many times the size of the archive it may unpack into.
+    pub max_ratio: u64,
+    pub max_files: u64,
+}
+
+/// How far along the extraction is, in bytes of the archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtractProgress {
+    pub done: u64,
+    pub total: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExtractError {
+    #[error("{0} is outside the folder the archive is extracted into")]
+    UnsafePath(String),
+    #[error("it unpacks into more than {0} bytes, which looks like a zip bomb")]
+    TooLarge(u64),
+    #[error("it has more than {0} files")]
+    TooManyFiles(u64),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+}
+
+/// Counts what has been written, to stop once an archive unpacks into more
+/// than it may.
+struct Budget {
+    bytes: u64,
+    max_bytes: u64,
+    files: u64,
+    max_files: u64,
+}
+
+impl Budget {
+    fn add_file(&mut self) -> Result<(), ExtractError> {
+        self.files += 1;
+        if self.files > self.max_files {
+            return Err(ExtractError::TooManyFiles(self.max_files));
+        }
+        Ok(())
+    }
+
+    /// Write out a file, counting the bytes that are actually written rather
+    /// than the size the archive claims.
+    fn copy(&mut self, reader: &mut impl Read, path: &Path) -> Result<(), ExtractError> {
+        self.add_file()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = File::create(path)?;
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            self.bytes += read as u64;
+            if self.bytes > self.max_bytes {
+                return Err(ExtractError::TooLarge(self.max_bytes));
+            }
+            io::Write::write_all(&mut file, &buffer[..read])?;
+        }
+        Ok(())
+    }
+}
+
+/// Passes on how much of the archive has been read.
+struct Counting<R, F> {
+    inner: R,
+    read: u64,
+    progress: F,
+}
+
+impl<R: Read, F: FnMut(u64)> Read for Counting<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.read += read as u64;
+        (self.progress)(self.read);
+        Ok(read)
+    }
+}
+
+/// Where an entry goes, relative to the extraction folder. Returns `None`
+/// for the folder itself.
+fn entry_path(name: &Path) -> Result<Option<PathBuf>, ExtractError> {
+    let mut path = PathBuf::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => return Err(ExtractError::UnsafePath(name.display().to_string())),
+        }
+    }
+    Ok((!path.as_os_str().is_empty()).then_some(path))
+}
+
+/// Give extracted files the permissions they had, minus anything special,
+/// so scripts stay runnable.
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: Option<u32>) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    match mode {
+        Some(mode) => fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: Option<u32>) -> io::Result<()> {
+    Ok(())
+}
+
+fn extract_tar(reader: impl Read, into: &Path, budget: &mut Budget) -> Result<(), ExtractError> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        let Some(path) = entry_path(&name)? else {
+            continue;
+        };
+        let target = into.join(&path);
+        match entry.header().entry_type() {
+            tar::EntryType::Directory => {
+                budget.add_file()?;
+                fs::create_dir_all(&target)?;
+            }
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                let mode = entry.header().mode().ok();
+                budget.copy(&mut entry, &target)?;
+                set_mode(&target, mode)?;
+            }
+            other => debug!("Skipping {}, a {:?} entry", name.display(), other),
+        }
+    }
+    Ok(())
+}
+
+fn extract_zip(
+    reader: impl Read + Seek,
+    into: &Path,
+    budget: &mut Budget,
+    mut progress: impl FnMut(u64),
+) -> Result<(), ExtractError> {
+    let mut archive = zip::ZipArchive::new(reader)?;
+    let mut done = 0;
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index)?;
+        let Some(name) = file.enclosed_name() else {
+            return Err(ExtractError::UnsafePath(file.name().to_string()));
+        };
+        let Some(path) = entry_path(&name)? else {
+            continue;
+        };
+        let target = into.join(&path);
+        if file.is_dir() {
+            budget.add_file()?;
+            fs::create_dir_all(&target)?;
+        } else if file.is_symlink() {
+            debug!("Skipping {}, a link", name.display());
+        } else {
+            let mode = file.unix_mode();
+            budget.copy(&mut file, &target)?;
+            set_mode(&target, mode)?;
+        }
+        done += file.compressed_size();
+        progress(done);
+    }
+    Ok(())
+}
+
+/// Pick a folder next to the archive that doesn't exist yet.
+fn free_folder(parent: &Path, name: &str) -> PathBuf {
+    let mut folder = parent.join(name);
+    let mut number = 2;
+    while folder.exists() {
+        folder = parent.join(format!("{} ({})", name, number));
+        number += 1;
+    }
+    folder
+}
+
+/// Extract the archive next to it, and return the folder it was extracted
+/// into. `progress` is called with how many bytes of the archive have been
+/// read, as it goes. This blocks, so run it on a blocking thread.
+pub fn extract(
+    archive: &Path,
+    kind: ArchiveKind,
+    stem: &str,
+    settings: &ExtractSettings,
+    mut progress: impl FnMut(ExtractProgress),
+) -> Result<PathBuf, ExtractError> {
+    let parent = archive.parent().unwrap_or(Path::new(""));
+    let file = File::open(archive)?;
+    let total = file.metadata()?.len();
+    let mut budget = Budget {
+        bytes: 0,
+        max_bytes: total.saturating_mul(settings.max_ratio),
+        files: 0,
+        max_files: settings.max_files,
+    };
+    let temporary = parent.join(format!(".{}.tmp", Ulid::new()));
+    fs::create_dir(&temporary)?;
+
+    let mut report = |done| progress(ExtractProgress { done, total });
+    let file = BufReader::new(file);
+    let result = match kind {
+        // Zip files are read out of order, so the progress is counted by
+        // entry instead.
+        ArchiveKind::Zip => extract_zip(file, &temporary, &mut budget, &mut report),
+        kind => {
+            let reader = Counting {
+                inner: file,
+                read: 0,
+                progress: &mut report,
+            };
+            match kind {
+                ArchiveKind::TarGz => extract_tar(GzDecoder::new(reader), &temporary, &mut budget),
+                ArchiveKind::TarXz => extract_tar(XzDecoder::new(reader), &temporary, &mut budget),
+                _ => extract_tar(reader, &temporary, &mut budget),
+            }
+        }
+    };
+    let folder = result.and_then(|()| {
+        let folder = free_folder(parent, stem);
+        fs::rename(&temporary, &folder)?;
+        Ok(folder)
+    });
+    if folder.is_err() {
+        let _ = fs::remove_dir_all(&temporary);
+    }
+    folder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_path_rejects_escaping_paths() {
+        for name in ["../x", "/abs", "a/../../b", "a/.."] {
+            assert!(
+                matches!(
+                    entry_path(Path::new(name)),
+                    Err(ExtractError::UnsafePath(_))
+                ),
+                "{} should be rejected",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn entry_path_accepts_relative_paths() {
+        assert_eq!(
+            entry_path(Path::new("./a/b")).unwrap(),
+            Some(PathBuf::from("a/b"))
+        );
+        assert_eq!(
+            entry_path(Path::new("a/./b/")).unwrap(),
+            Some(PathBuf::from("a/b"))
+        );
+        assert_eq!(entry_path(Path::new("./")).unwrap(), None);
+    }
+}
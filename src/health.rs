@@ -0,0 +1,102 @@
+//! Health checks for Docker and Kubernetes, which can't log in.
+//!
+//! `/healthz` only says the process is up. `/readyz` also checks that the
+//! store answers, that the coordinator is responding, and that the download
+//! folder can be written to.
+use std::{future::Future, time::Duration};
+
+use actix_web::{get, web::Data, HttpResponse, Responder};
+use ractor::{call_t, ActorRef};
+use serde::Serialize;
+use tokio::{fs, time::timeout};
+use ulid::Ulid;
+
+use crate::{
+    download_actor::{Coordinator, CoordinatorMsg},
+    store::HistoryStore,
+};
+
+/// How long each check may take before it counts as failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize)]
+struct Check {
+    name: &'static str,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+async fn check<F>(name: &'static str, future: F) -> Check
+where
+    F: Future<Output = Result<(), String>>,
+{
+    let result = match timeout(CHECK_TIMEOUT, future).await {
+        Ok(result) => result,
+        Err(_) => Err(format!(
+            "No answer within {} seconds",
+            CHECK_TIMEOUT.as_secs()
+        )),
+    };
+    Check {
+        name,
+        ok: result.is_ok(),
+        error: result.err(),
+    }
+}
+
+/// Downloads are saved relative to the working directory.
+async fn check_download_folder() -> Result<(), String> {
+    let probe = format!(".readyz-{}.tmp", Ulid::new());
+    fs::write(&probe, b"")
+        .await
+        .map_err(|err| format!("The download folder is not writable: {}", err))?;
+    fs::remove_file(&probe)
+        .await
+        .map_err(|err| format!("Failed to clean up {}: {}", probe, err))
+}
+
+#[get("/healthz")]
+#[tracing::instrument(level = "trace")]
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+#[get("/readyz")]
+#[tracing::instrument(level = "trace", skip(history, coordinator))]
+async fn readyz(
+    history: Data<HistoryStore>,
+    coordinator: Data<ActorRef<Coordinator>>,
+) -> impl Responder {
+    let checks = vec![
+        check("store", async {
+            // Any read will do, the key doesn't have to exist.
+            history
+                .get("readyz")
+                .await
+                .map(|_| ())
+                .map_err(|err| err.to_string())
+        })
+        .await,
+        check("coordinator", async {
+            call_t!(
+                coordinator,
+                CoordinatorMsg::Ping,
+                CHECK_TIMEOUT.as_millis() as u64
+            )
+            .map_err(|err| err.to_string())
+        })
+        .await,
+        check("download_folder", check_download_folder()).await,
+    ];
+    let ready = checks.iter().all(|check| check.ok);
+    let body = serde_json::json!({
+        "status": if ready { "ok" } else { "error" },
+        "checks": checks,
+    });
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
@@ -0,0 +1,151 @@
+//! A command to run after each download completes, to unpack or transcode the
+//! file, or to tell another app to look for it.
+//!
+//! The command runs through the shell once the file is in its final place,
+//! with the details of the download in environment variables. Its output is
+//! kept with the download, so it can be checked when something goes wrong.
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{process::Command, time::timeout};
+
+use crate::store::HistoryEntry;
+
+/// How much of each of stdout and stderr to keep. The end is kept since
+/// that's where errors usually are.
+const MAX_OUTPUT: usize = 16 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PostHook {
+    /// Run with `sh -c`, or `cmd /C` on Windows.
+    pub command: String,
+    /// How long the command may run before it is killed.
+    pub timeout: Duration,
+    /// Whether the download is marked as failed if the command fails.
+    pub fail_download: bool,
+}
+
+/// How the hook went, stored with the download.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HookResult {
+    /// The exit code, missing if the command was killed or couldn't start.
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+    pub stdout: String,
+    /// Also says why the command couldn't start, if it didn't.
+    pub stderr: String,
+}
+
+impl HookResult {
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+
+    fn not_started(err: impl std::fmt::Display) -> Self {
+        HookResult {
+            exit_code: None,
+            timed_out: false,
+            stdout: String::new(),
+            stderr: format!("Failed to run the hook: {}", err),
+        }
+    }
+}
+
+impl std::fmt::Display for HookResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.exit_code {
+            _ if self.timed_out => f.write_str("timed out"),
+            Some(code) => write!(f, "exited with {}", code),
+            None if self.stderr.is_empty() => f.write_str("was killed"),
+            None => f.write_str(&self.stderr),
+        }
+    }
+}
+
+/// Downloads are saved relative to the working directory, but the command may
+/// change directories.
+fn absolute(path: &str) -> PathBuf {
+    std::env::current_dir()
+        .map(|dir| dir.join(path))
+        .unwrap_or_else(|_| Path::new(path).to_path_buf())
+}
+
+fn last_part(output: &[u8]) -> String {
+    let start = output.len().saturating_sub(MAX_OUTPUT);
+    String::from_utf8_lossy(&output[start..]).into_owned()
+}
+
+impl PostHook {
+    fn command(&self) -> Command {
+        let mut command = if cfg!(windows) {
+            let mut command = std::process::Command::new("cmd");
+            command.arg("/C");
+            command
+        } else {
+            let mut command = std::process::Command::new("sh");
+            command.arg("-c");
+            command
+        };
+        command.arg(&self.command);
+        // In a group of its own, so whatever the shell starts can be killed
+        // along with it.
+        #[cfg(unix)]
+        command.process_group(0);
+        command.into()
+    }
+
+    /// Run the command for a completed download.
+    pub async fn run(&self, entry: &HistoryEntry) -> HookResult {
+        let mut command = self.command();
+        if let Some(folder) = &entry.extracted_to {
+            command.env("HTTP_DROGUE_EXTRACTED_PATH", absolute(folder));
+        }
+        let child = command
+            .env("HTTP_DROGUE_ID", &entry.id)
+            .env("HTTP_DROGUE_URL", &entry.url)
+            .env("HTTP_DROGUE_PATH", absolute(&entry.path))
+            .env("HTTP_DROGUE_SIZE", entry.size.to_string())
+            .env("HTTP_DROGUE_SHA256", &entry.sha256)
+            .env("HTTP_DROGUE_OWNER", &entry.owner)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // Timing out drops the child, which kills it.
+            .kill_on_drop(true)
+            .spawn();
+        let child = match child {
+            Ok(child) => child,
+            Err(err) => return HookResult::not_started(err),
+        };
+        let pid = child.id();
+        match timeout(self.timeout, child.wait_with_output()).await {
+            Ok(Ok(output)) => HookResult {
+                exit_code: output.status.code(),
+                timed_out: false,
+                stdout: last_part(&output.stdout),
+                stderr: last_part(&output.stderr),
+            },
+            Ok(Err(err)) => HookResult::not_started(err),
+            Err(_) => {
+                #[cfg(unix)]
+                if let Some(pid) = pid {
+                    // SAFETY: kill has no memory safety requirements. The
+                    // group is around as long as anything in it is running.
+                    unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+                }
+                HookResult {
+                    exit_code: None,
+                    timed_out: true,
+                    stdout: String::new(),
+                    stderr: String::new(),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,260 @@
+//! Parse lists of URLs to download.
+//!
+//! This accepts plain lists with one URL per line, and the input file format
+//! used by aria2. In the aria2 format, indented lines following a URL set
+//! options for that download:
+//!
+//! ```text
+//! https://example.com/some/file.iso
+//!   out=renamed.iso
+//!   dir=isos
+//!   header=Authorization: Bearer abc
+//!   extract=true
+//! ```
+//!
+//! `extract` isn't an aria2 option. It says whether to extract the archive
+//! once it is downloaded, overriding the `[extract]` settings.
+//!
+//! aria2 lists mirrors of the same file on one line separated by tabs. We
+//! only download from one place, so the first URL on the line is used.
+use std::path::{Component, Path};
+
+use reqwest::{
+    header::{HeaderName, HeaderValue},
+    Url,
+};
+use tracing::debug;
+
+use crate::store::DownloadOptions;
+
+#[derive(Debug, Clone)]
+pub struct BatchEntry {
+    pub url: String,
+    pub options: DownloadOptions,
+}
+
+#[derive(Debug, Clone)]
+pub struct InvalidEntry {
+    /// The line number in the input, starting from 1.
+    pub line: usize,
+    pub text: String,
+    pub reason: String,
+}
+
+pub fn parse(input: &str) -> Vec<Result<BatchEntry, InvalidEntry>> {
+    let mut entries: Vec<Result<BatchEntry, InvalidEntry>> = Vec::new();
+    // Whether the options that follow belong to the last entry. If the URL
+    // was invalid, we skip its options too rather than reporting each of them.
+    let mut accepting_options = false;
+
+    for (index, line) in input.lines().enumerate() {
+        let invalid = |reason: &str| InvalidEntry {
+            line: index + 1,
+            text: line.to_string(),
+            reason: reason.to_string(),
+        };
+
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+
+        if line.starts_with(char::is_whitespace) {
+            match entries.last_mut() {
+                Some(Ok(entry)) if accepting_options => {
+                    if let Err(reason) = parse_option(line.trim(), &mut entry.options) {
+                        let url = entry.url.clone();
+                        *entries.last_mut().unwrap() = Err(InvalidEntry {
+                            line: index + 1,
+                            text: url,
+                            reason,
+                        });
+                        accepting_options = false;
+                    }
+                }
+                Some(Err(_)) => {}
+                _ => entries.push(Err(invalid("Option does not follow a URL"))),
+            }
+            continue;
+        }
+
+        let url = line.split('\t').next().unwrap_or_default().trim();
+        match parse_url(url) {
+            Ok(url) => {
+                entries.push(Ok(BatchEntry {
+                    url,
+                    options: DownloadOptions::default(),
+                }));
+                accepting_options = true;
+            }
+            Err(reason) => {
+                entries.push(Err(invalid(&reason)));
+                accepting_options = false;
+            }
+        }
+    }
+
+    entries
+}
+
+/// Checks that the URL is one we can download from.
+pub fn parse_url(url: &str) -> Result<String, String> {
+    let parsed = Url::parse(url).map_err(|err| format!("Not a valid URL: {}", err))?;
+    match parsed.scheme() {
+        "http" | "https" => Ok(url.to_string()),
+        scheme => Err(format!("Unsupported scheme {}", scheme)),
+    }
+}
+
+fn parse_option(option: &str, options: &mut DownloadOptions) -> Result<(), String> {
+    let Some((key, value)) = option.split_once('=') else {
+        return Err(format!("Option {} is not in the form key=value", option));
+    };
+    let value = value.trim();
+    match key.trim() {
+        "out" => options.out = Some(validate_out(value)?),
+        "dir" => options.dir = Some(validate_dir(value)?),
+        "header" => {
+            let Some((name, header_value)) = value.split_once(':') else {
+                return Err(format!("Header {} is not in the form Name: value", value));
+            };
+            options
+                .headers
+                .push(validate_header(name.trim(), header_value.trim())?);
+        }
+        "extract" => match value {
+            "true" => options.extract = Some(true),
+            "false" => options.extract = Some(false),
+            _ => return Err(format!("extract must be true or false, not {}", value)),
+        },
+        other => {
+            // aria2 has a lot of options, most of which don't make sense for
+            // us. Skip them so aria2 input files can be used as is.
+            debug!("Ignoring unsupported option {}", other);
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the output name is a plain file name.
+pub fn validate_out(out: &str) -> Result<String, String> {
+    if !is_relative_path(out) || out.contains(['/', '\\']) {
+        return Err(format!("Output name {} must be a plain file name", out));
+    }
+    Ok(out.to_string())
+}
+
+/// Checks that the folder is inside the download folder.
+pub fn validate_dir(dir: &str) -> Result<String, String> {
+    if !is_relative_path(dir) {
+        return Err(format!("Folder {} must be inside the download folder", dir));
+    }
+    Ok(dir.to_string())
+}
+
+pub fn validate_header(name: &str, value: &str) -> Result<(String, String), String> {
+    if HeaderName::from_bytes(name.as_bytes()).is_err() || HeaderValue::from_str(value).is_err() {
+        return Err(format!("Header {}: {} is not valid", name, value));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Checks that the path stays within the folder it is joined to.
+pub fn is_relative_path(path: &str) -> bool {
+    !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reason(result: &Result<BatchEntry, InvalidEntry>) -> &str {
+        &result.as_ref().unwrap_err().reason
+    }
+
+    #[test]
+    fn options_attach_to_the_preceding_url() {
+        let entries = parse(
+            "https://example.com/a.iso\n\
+             \x20 out=renamed.iso\n\
+             \tdir=isos/linux\n\
+             \x20 header=Authorization: Bearer abc\n\
+             \x20 extract=true\n\
+             https://example.com/b.iso\thttps://mirror.example.com/b.iso\n",
+        );
+        assert_eq!(entries.len(), 2);
+        let first = entries[0].as_ref().unwrap();
+        assert_eq!(first.url, "https://example.com/a.iso");
+        assert_eq!(first.options.out.as_deref(), Some("renamed.iso"));
+        assert_eq!(first.options.dir.as_deref(), Some("isos/linux"));
+        assert_eq!(
+            first.options.headers,
+            vec![("Authorization".to_string(), "Bearer abc".to_string())]
+        );
+        assert_eq!(first.options.extract, Some(true));
+        let second = entries[1].as_ref().unwrap();
+        assert_eq!(second.url, "https://example.com/b.iso");
+        assert!(second.options.out.is_none() && second.options.headers.is_empty());
+    }
+
+    #[test]
+    fn skips_comments_and_blank_lines() {
+        let entries = parse(
+            "# downloads\n\
+             \n\
+             https://example.com/a.iso\n\
+             \x20 # not an option\n\
+             \x20\x20\n\
+             \x20 out=a.iso\n",
+        );
+        assert_eq!(entries.len(), 1);
+        let entry = entries[0].as_ref().unwrap();
+        assert_eq!(entry.options.out.as_deref(), Some("a.iso"));
+    }
+
+    #[test]
+    fn reports_invalid_lines() {
+        let entries = parse(
+            "\x20 out=orphan.iso\n\
+             ftp://example.com/a.iso\n\
+             \x20 out=skipped.iso\n\
+             https://example.com/b.iso\n\
+             \x20 extract=maybe\n",
+        );
+        assert_eq!(entries.len(), 3);
+        assert_eq!(reason(&entries[0]), "Option does not follow a URL");
+        assert_eq!(reason(&entries[1]), "Unsupported scheme ftp");
+        let invalid = entries[2].as_ref().unwrap_err();
+        assert_eq!(invalid.line, 5);
+        assert_eq!(invalid.text, "https://example.com/b.iso");
+    }
+
+    #[test]
+    fn rejects_paths_outside_the_download_folder() {
+        for out in ["../x", "a/b", "a\\b", "/etc/passwd", "..", ""] {
+            assert!(validate_out(out).is_err(), "out={} should be rejected", out);
+        }
+        for dir in ["/tmp", "../x", "a/../../b", "a/..", ""] {
+            assert!(validate_dir(dir).is_err(), "dir={} should be rejected", dir);
+        }
+        assert_eq!(validate_out("file.iso").unwrap(), "file.iso");
+        assert_eq!(validate_dir("./isos/linux").unwrap(), "./isos/linux");
+
+        let entries = parse("https://example.com/a.iso\n  dir=../../etc\n");
+        assert!(entries[0].is_err());
+    }
+
+    #[test]
+    fn rejects_headers_with_line_breaks() {
+        assert!(validate_header("X-Test", "a\r\nSet-Cookie: b").is_err());
+        assert!(validate_header("X-Test", "a\nb").is_err());
+        assert!(validate_header("X-Test", "a\rb").is_err());
+        assert!(validate_header("X Test", "a").is_err());
+        assert!(validate_header("X-Test", "a b").is_ok());
+
+        let entries = parse("https://example.com/a.iso\n  header=X-Test: a\rb\n");
+        assert!(entries[0].is_err());
+    }
+}
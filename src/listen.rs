@@ -0,0 +1,68 @@
+//! Where the server listens for connections.
+use std::{
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Listener {
+    /// An IPv4 or IPv6 address and port.
+    Tcp(SocketAddr),
+    /// A Unix domain socket, for a reverse proxy running on the same machine.
+    Unix(PathBuf),
+}
+
+impl FromStr for Listener {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("The path of the Unix socket is missing".to_string());
+            }
+            return Ok(Listener::Unix(PathBuf::from(path)));
+        }
+        s.parse().map(Listener::Tcp).map_err(|_| {
+            format!(
+                "Invalid listen address {}, expected an address and port like 0.0.0.0:8080 or [::]:8080, or unix:/path/to/socket",
+                s
+            )
+        })
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(addr) => write!(f, "{}", addr),
+            Listener::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Parse a list of listen addresses separated by commas or whitespace.
+pub fn parse_listeners(list: &str) -> Result<Vec<Listener>, String> {
+    let listeners = list
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|listener| !listener.is_empty())
+        .map(|listener| listener.parse())
+        .collect::<Result<Vec<_>, _>>()?;
+    if listeners.is_empty() {
+        return Err("There is nowhere to listen, give at least one address".to_string());
+    }
+    Ok(listeners)
+}
+
+/// Remove a socket left behind by a previous run, which would stop us from
+/// binding to it again.
+#[cfg(unix)]
+pub fn remove_stale_socket(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
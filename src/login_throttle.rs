@@ -0,0 +1,131 @@
+//! Slow down password guessing by locking out IP addresses and usernames
+//! after too many failed logins.
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use tracing::warn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThrottleLimits {
+    /// How many failed logins are allowed within `window` before locking out.
+    pub max_failures: u32,
+    /// How long failed logins are remembered.
+    pub window: Duration,
+    /// How long a lockout lasts.
+    pub lockout: Duration,
+}
+
+impl Default for ThrottleLimits {
+    fn default() -> Self {
+        ThrottleLimits {
+            max_failures: 10,
+            window: Duration::from_secs(15 * 60),
+            lockout: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    first: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Failures {
+    fn expired(&self, now: Instant, window: Duration) -> bool {
+        match self.locked_until {
+            Some(until) => until <= now,
+            None => now.duration_since(self.first) > window,
+        }
+    }
+}
+
+/// Keeps count of failed logins, separately for each IP address and for
+/// each username. Either one reaching the limit locks out the login.
+#[derive(Debug, Default)]
+pub struct LoginThrottle {
+    failures: Mutex<HashMap<String, Failures>>,
+    /// Can be changed while running, when the configuration is reloaded.
+    limits: RwLock<ThrottleLimits>,
+}
+
+fn keys(ip: Option<IpAddr>, username: Option<&str>) -> Vec<String> {
+    let mut keys = Vec::with_capacity(2);
+    if let Some(ip) = ip {
+        keys.push(format!("ip:{}", ip));
+    }
+    if let Some(username) = username {
+        keys.push(format!("user:{}", username));
+    }
+    keys
+}
+
+impl LoginThrottle {
+    pub fn new(limits: ThrottleLimits) -> Self {
+        LoginThrottle {
+            failures: Mutex::default(),
+            limits: RwLock::new(limits),
+        }
+    }
+
+    pub fn set_limits(&self, limits: ThrottleLimits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    /// If the login is locked out, returns how long until it can be tried
+    /// again.
+    pub fn check(&self, ip: Option<IpAddr>, username: Option<&str>) -> Option<Duration> {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+        keys(ip, username)
+            .iter()
+            .filter_map(|key| failures.get(key)?.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+            .max()
+    }
+
+    pub fn record_failure(&self, ip: Option<IpAddr>, username: Option<&str>) {
+        let now = Instant::now();
+        let limits = *self.limits.read().unwrap();
+        let mut failures = self.failures.lock().unwrap();
+        // Forget old failures, so the map doesn't keep growing.
+        failures.retain(|_, entry| !entry.expired(now, limits.window));
+        for key in keys(ip, username) {
+            let entry = failures.entry(key.clone()).or_insert(Failures {
+                count: 0,
+                first: now,
+                locked_until: None,
+            });
+            entry.count += 1;
+            if entry.count >= limits.max_failures && entry.locked_until.is_none() {
+                warn!(
+                    "Locking out {} for {} after {} failed logins",
+                    key,
+                    humantime::format_duration(limits.lockout),
+                    entry.count
+                );
+                entry.locked_until = Some(now + limits.lockout);
+            }
+        }
+    }
+
+    /// Forget the failed logins of the user. Failures from the IP address
+    /// are kept, otherwise logging into one account would let someone keep
+    /// guessing the passwords of others.
+    pub fn record_success(&self, username: &str) {
+        let mut failures = self.failures.lock().unwrap();
+        let key = format!("user:{}", username);
+        if failures
+            .get(&key)
+            .is_some_and(|entry| entry.locked_until.is_none())
+        {
+            failures.remove(&key);
+        }
+    }
+}
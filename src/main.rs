@@ -1,54 +1,182 @@
+mod api;
 mod avg_range;
+mod config;
+mod csrf;
+mod destination;
+mod disk;
 mod download_actor;
+mod email;
+mod events;
+mod extract;
+mod health;
+mod hook;
+mod input_file;
+mod listen;
+mod login_throttle;
+mod metrics;
+mod mqtt;
+mod origin;
+mod proxy;
+mod push;
+mod sessions;
 mod store;
+mod tls;
+mod tokens;
+mod users;
+mod webhooks;
 
-use std::{env, process::exit};
+use std::{
+    io::{self, BufRead},
+    net::IpAddr,
+    path::Path,
+    process::exit,
+    time::{Duration, UNIX_EPOCH},
+};
 
+use actix_multipart::Multipart;
 use actix_web::{
+    dev::ServiceRequest,
+    error::{
+        ErrorBadRequest, ErrorForbidden, ErrorInternalServerError, ErrorNotFound,
+        ErrorPayloadTooLarge, ErrorUnauthorized, InternalError,
+    },
     get,
+    http::Method,
     middleware::DefaultHeaders,
     post,
-    web::{Data, Form},
-    App, HttpResponse, HttpServer, Responder,
+    web::{self, Data, Form, Query, ReqData},
+    App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder,
 };
 use actix_web_httpauth::{
-    extractors::{basic, AuthenticationError},
+    extractors::{
+        basic::{self, BasicAuth},
+        bearer::{self, BearerAuth},
+        AuthenticationError,
+    },
     middleware::HttpAuthentication,
 };
 use actix_web_rust_embed_responder::IntoResponse;
 use askama::Template;
-use cuttlestore::Cuttlestore;
-use download_actor::{url_to_filename, Coordinator};
+use clap::Parser;
+use config::{Cli, Command, Config, Settings};
+use csrf::{Csrf, CsrfKey};
+use download_actor::{url_to_filename, Coordinator, DuplicatePolicy, Enqueued, Owner};
+use email::Notifier;
+use events::EventBus;
+use extract::ExtractProgress;
 use futures::StreamExt;
-use lazy_static::lazy_static;
-use ractor::{cast, Actor, ActorRef};
+use hook::HookResult;
+use input_file::{BatchEntry, InvalidEntry};
+use listen::Listener;
+use login_throttle::LoginThrottle;
+use metrics::MetricsAuth;
+use proxy::{ClientInfo, ProxyConfig};
+use push::{Push, VapidKey};
+use ractor::{call, Actor, ActorRef};
 use rust_embed_for_web::RustEmbed;
-use scrypt::{
-    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, Salt},
-    Params, Scrypt,
-};
 use serde::Deserialize;
-use store::Progress;
-use tracing::{debug, error};
+use sessions::{LoginConfig, LoginMode};
+use store::{
+    ApiToken, HistoryEntry, HistoryStore, Progress, Role, SessionStore, Stores, TokenScope,
+    TokenStore, User, UserStore,
+};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::{
     fmt::format::FmtSpan, prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt,
 };
+use users::{AdminAccount, CurrentUser, PasswordCache, QuotaError};
+use webhooks::{Delivery, DeliveryStatus, Webhook, Webhooks};
 
-use crate::{
-    download_actor::{CoordinatorMsg, StartDownload},
-    store::DownloadProgressStore,
-};
+use crate::download_actor::CoordinatorMsg;
 
 #[derive(Template)]
 #[template(path = "index.html")]
-
-struct HomeTemplate;
+struct HomeTemplate {
+    /// Where the app is, links in the page start with this.
+    base: String,
+    username: String,
+    admin: bool,
+    /// Whether the user logged in through the login page, and can log out.
+    logout: bool,
+    csrf_token: String,
+    /// The key to subscribe to push notifications with, if they are on.
+    push_key: Option<String>,
+}
 
 #[derive(Template)]
 #[template(path = "download_progress.html")]
-
 struct DownloadListTemplate {
+    base: String,
     files: Vec<ProgressDisplay>,
+    csrf_token: String,
+    /// Free space where downloads in progress are written, if it is known.
+    temp_free: Option<String>,
+    /// Free space in the user's download folder, if it is known.
+    downloads_free: Option<String>,
+    /// Set while the downloads are held because the disk is nearly full.
+    low_disk: Option<LowDiskDisplay>,
+}
+
+#[derive(Debug)]
+struct LowDiskDisplay {
+    free: String,
+    min_free_space: String,
+}
+
+#[derive(Template)]
+#[template(path = "batch_summary.html")]
+struct BatchSummaryTemplate {
+    base: String,
+    accepted: Vec<String>,
+    duplicates: Vec<String>,
+    attached: Vec<String>,
+    invalid: Vec<InvalidEntry>,
+}
+
+#[derive(Template)]
+#[template(path = "history.html")]
+struct HistoryTemplate {
+    base: String,
+    query: String,
+    entries: Vec<HistoryDisplay>,
+    csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "users.html")]
+struct UsersTemplate {
+    base: String,
+    /// The admin account from the environment, which can't be changed here.
+    admin_username: String,
+    users: Vec<UserDisplay>,
+    csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "webhooks.html")]
+struct WebhooksTemplate {
+    base: String,
+    webhooks: Vec<WebhookDisplay>,
+    deliveries: Vec<DeliveryDisplay>,
+}
+
+#[derive(Template)]
+#[template(path = "tokens.html")]
+struct TokensTemplate {
+    base: String,
+    tokens: Vec<TokenDisplay>,
+    /// A token that was just created. It's only shown this once.
+    created: Option<String>,
+    csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "login.html")]
+struct LoginTemplate {
+    base: String,
+    error: Option<String>,
 }
 
 /// A version of `Progress` that is suitable for display in a template.
@@ -58,7 +186,9 @@ struct DownloadListTemplate {
 /// easier to do it this way.
 #[derive(Debug)]
 struct ProgressDisplay {
+    pub id: String,
     pub failed: bool,
+    pub paused: bool,
     pub url: String,
     pub name: String,
     pub percent: Option<String>,
@@ -66,6 +196,119 @@ struct ProgressDisplay {
     pub total: Option<String>,
     pub speed: String,
     pub time_estimate: Option<String>,
+    pub error: Option<String>,
+    pub hook: Option<HookDisplay>,
+    /// How much of the archive has been extracted, in percent, while it is
+    /// extracted.
+    pub extracting: Option<String>,
+}
+
+/// A version of `HistoryEntry` that is suitable for display in a template.
+#[derive(Debug)]
+struct HistoryDisplay {
+    pub id: String,
+    pub url: String,
+    pub path: String,
+    pub size: String,
+    pub duration: String,
+    pub speed: String,
+    pub sha256: String,
+    pub completed_at: String,
+    pub hook: Option<HookDisplay>,
+    pub extracted_to: Option<String>,
+}
+
+/// A version of `HookResult` that is suitable for display in a template.
+#[derive(Debug)]
+struct HookDisplay {
+    pub ok: bool,
+    pub summary: String,
+    /// stdout followed by stderr.
+    pub output: String,
+}
+
+/// A version of `ApiToken` that is suitable for display in a template.
+#[derive(Debug)]
+struct TokenDisplay {
+    pub id: String,
+    pub name: String,
+    pub scope: &'static str,
+    pub created_at: String,
+    pub expires_at: String,
+    pub expired: bool,
+}
+
+/// A version of `User` that is suitable for display in a template.
+#[derive(Debug)]
+struct UserDisplay {
+    pub username: String,
+    pub role: &'static str,
+    /// The quota in GiB, as entered in the form. Empty if there is none.
+    pub quota_gib: String,
+    pub quota: String,
+    pub created_at: String,
+}
+
+/// A version of `Webhook` that is suitable for display in a template.
+#[derive(Debug)]
+struct WebhookDisplay {
+    pub url: String,
+    pub events: String,
+    pub signed: bool,
+}
+
+impl From<Webhook> for WebhookDisplay {
+    fn from(webhook: Webhook) -> Self {
+        let events = if webhook.events.is_empty() {
+            "all".to_string()
+        } else {
+            webhook
+                .events
+                .iter()
+                .map(|event| event.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        WebhookDisplay {
+            url: webhook.url.to_string(),
+            events,
+            signed: webhook.secret.is_some(),
+        }
+    }
+}
+
+/// A version of `Delivery` that is suitable for display in a template.
+#[derive(Debug)]
+struct DeliveryDisplay {
+    pub url: String,
+    pub event: String,
+    pub download_id: String,
+    pub attempts: u32,
+    pub status: &'static str,
+    pub last_result: String,
+    pub updated_at: String,
+}
+
+impl From<Delivery> for DeliveryDisplay {
+    fn from(delivery: Delivery) -> Self {
+        DeliveryDisplay {
+            url: delivery.url,
+            event: delivery.event.to_string(),
+            download_id: delivery.download_id,
+            attempts: delivery.attempts,
+            status: match delivery.status {
+                DeliveryStatus::Pending => "pending",
+                DeliveryStatus::Delivered => "delivered",
+                DeliveryStatus::Failed => "failed",
+            },
+            last_result: delivery.last_result.unwrap_or_default(),
+            updated_at: human_date(delivery.updated_at),
+        }
+    }
+}
+
+fn human_date(timestamp: u64) -> String {
+    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(timestamp)).to_string()
 }
 
 fn human_speed(speed: f64) -> String {
@@ -111,166 +354,1363 @@ impl From<Progress> for ProgressDisplay {
     #[tracing::instrument(level = "debug")]
     fn from(value: Progress) -> Self {
         ProgressDisplay {
+            id: value.id,
             failed: value.failed,
+            paused: value.paused,
             name: url_to_filename(&value.url),
             url: value.url,
             percent: value
                 .total
                 .map(|total| format!("{:.2}", value.progress as f64 / total as f64 * 100f64)),
             progress: human_bytes(value.progress),
-            total: value.total.map(|total| human_bytes(total)),
+            total: value.total.map(human_bytes),
             speed: human_speed(value.speed),
             time_estimate: value
                 .total
-                .map(|total| human_time((total - value.progress) as f64 / value.speed)),
+                .map(|total| human_time(total.saturating_sub(value.progress) as f64 / value.speed)),
+            error: value.error,
+            hook: value.hook.map(HookDisplay::from),
+            extracting: value
+                .extracting
+                .map(|extracting| format!("{:.0}", extract_percent(extracting))),
+        }
+    }
+}
+
+impl From<HistoryEntry> for HistoryDisplay {
+    fn from(value: HistoryEntry) -> Self {
+        HistoryDisplay {
+            id: value.id,
+            url: value.url,
+            path: value.path,
+            size: human_bytes(value.size),
+            duration: human_time(value.duration as f64),
+            speed: human_speed(value.speed),
+            sha256: value.sha256,
+            completed_at: human_date(value.completed_at),
+            hook: value.hook.map(HookDisplay::from),
+            extracted_to: value.extracted_to,
+        }
+    }
+}
+
+fn extract_percent(progress: ExtractProgress) -> f64 {
+    match progress.total {
+        0 => 100f64,
+        total => progress.done as f64 / total as f64 * 100f64,
+    }
+}
+
+impl From<HookResult> for HookDisplay {
+    fn from(value: HookResult) -> Self {
+        HookDisplay {
+            ok: value.success(),
+            summary: value.to_string(),
+            output: [value.stdout.trim_end(), value.stderr.trim_end()]
+                .into_iter()
+                .filter(|output| !output.is_empty())
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+}
+
+impl From<User> for UserDisplay {
+    fn from(value: User) -> Self {
+        UserDisplay {
+            username: value.username,
+            role: match value.role {
+                Role::Admin => "admin",
+                Role::User => "user",
+                Role::Viewer => "viewer",
+            },
+            quota_gib: value
+                .quota
+                .map(|quota| format!("{}", quota as f64 / GIB as f64))
+                .unwrap_or_default(),
+            quota: value
+                .quota
+                .map(human_bytes)
+                .unwrap_or_else(|| "None".to_string()),
+            created_at: human_date(value.created_at),
+        }
+    }
+}
+
+impl From<ApiToken> for TokenDisplay {
+    fn from(value: ApiToken) -> Self {
+        TokenDisplay {
+            id: value.id,
+            name: value.name,
+            scope: match value.scope {
+                TokenScope::ReadOnly => "Read only",
+                TokenScope::Add => "Add downloads",
+                TokenScope::Full => "Full control",
+            },
+            created_at: human_date(value.created_at),
+            expires_at: value
+                .expires_at
+                .map(human_date)
+                .unwrap_or_else(|| "Never".to_string()),
+            expired: value
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= store::unix_now()),
         }
     }
 }
 
 #[get("/")]
 #[tracing::instrument(level = "debug")]
-async fn home() -> impl Responder {
-    let response = HomeTemplate.render().unwrap();
+async fn home(
+    user: ReqData<CurrentUser>,
+    csrf: ReqData<Csrf>,
+    login: Data<LoginConfig>,
+    push: Data<Push>,
+    client: ClientInfo,
+) -> impl Responder {
+    let response = HomeTemplate {
+        base: client.base_path.clone(),
+        username: user.username.clone(),
+        admin: user.role == Role::Admin,
+        logout: login.mode == LoginMode::Form,
+        csrf_token: csrf.token().to_string(),
+        push_key: push.public_key(),
+    }
+    .render()
+    .unwrap();
     HttpResponse::Ok().content_type("text/html").body(response)
 }
 
 #[get("/list")]
 #[tracing::instrument(level = "debug")]
-async fn list(store: Data<DownloadProgressStore>) -> impl Responder {
-    let files = store.scan().await.unwrap();
-    let files = files.map(|x| x.unwrap().1);
-    let files = files.map(|x| x.into());
-    let files = files.collect::<Vec<_>>().await;
+async fn list(
+    coordinator: Data<ActorRef<Coordinator>>,
+    user: ReqData<CurrentUser>,
+    csrf: ReqData<Csrf>,
+    client: ClientInfo,
+) -> actix_web::Result<impl Responder> {
+    let files = call!(coordinator, CoordinatorMsg::List)
+        .map_err(|err| ErrorInternalServerError(err.to_string()))?;
+    let files = files
+        .into_iter()
+        .filter(|file| user.can_see(&file.owner))
+        .map(|x| x.into())
+        .collect();
+    let low_disk = call!(coordinator, CoordinatorMsg::LowDisk)
+        .map_err(|err| ErrorInternalServerError(err.to_string()))?
+        .map(|low_disk| LowDiskDisplay {
+            free: human_bytes(low_disk.free),
+            min_free_space: human_bytes(low_disk.min_free_space),
+        });
 
-    let response = DownloadListTemplate { files }.render().unwrap();
-    HttpResponse::Ok().content_type("text/html").body(response)
+    let response = DownloadListTemplate {
+        base: client.base_path.clone(),
+        files,
+        csrf_token: csrf.token().to_string(),
+        temp_free: disk::free_space(Path::new(disk::TEMP_DIR))
+            .ok()
+            .map(human_bytes),
+        downloads_free: disk::free_space(Path::new(&user.base_dir))
+            .ok()
+            .map(human_bytes),
+        low_disk,
+    }
+    .render()
+    .unwrap();
+    Ok(HttpResponse::Ok().content_type("text/html").body(response))
 }
 
 #[derive(Debug, Deserialize)]
 struct DownloadRequest {
     url: String,
-    restarting: Option<bool>,
+    duplicate: Option<DuplicatePolicy>,
+    #[serde(default)]
+    csrf_token: String,
+}
+
+/// Refuse new downloads from users whose folder is full.
+async fn check_quota(
+    user: &CurrentUser,
+    coordinator: &ActorRef<Coordinator>,
+) -> actix_web::Result<()> {
+    users::check_quota(user, coordinator)
+        .await
+        .map_err(|err| match err {
+            QuotaError::Exceeded { .. } => ErrorForbidden(err.to_string()),
+            QuotaError::Internal(_) => ErrorInternalServerError(err.to_string()),
+        })
+}
+
+/// Get a download that the user is allowed to change.
+async fn manageable_download(
+    coordinator: &ActorRef<Coordinator>,
+    user: &CurrentUser,
+    id: String,
+) -> actix_web::Result<Progress> {
+    call!(coordinator, CoordinatorMsg::Get, id)
+        .map_err(|err| ErrorInternalServerError(err.to_string()))?
+        .filter(|download| user.can_manage(&download.owner))
+        .ok_or_else(|| ErrorNotFound("No such download"))
 }
 
 #[post("/request_download")]
-#[tracing::instrument(level = "info", skip(store, coordinator))]
+#[tracing::instrument(level = "info", skip(coordinator))]
 async fn request_download(
     request: Form<DownloadRequest>,
-    store: Data<DownloadProgressStore>,
     coordinator: Data<ActorRef<Coordinator>>,
-) -> impl Responder {
+    user: ReqData<CurrentUser>,
+    csrf: ReqData<Csrf>,
+    client: ClientInfo,
+) -> actix_web::Result<impl Responder> {
+    csrf.check(&request.csrf_token)?;
     debug!("Requesting download of {}", request.url);
-    store
-        .put(
-            request.url.clone(),
-            &Progress {
-                target_file: None,
-                failed: false,
-                url: request.url.clone(),
-                progress: 0,
-                total: None,
-                speed: 0f64,
-            },
-        )
+    let request = request.into_inner();
+    let url = input_file::parse_url(&request.url).map_err(ErrorBadRequest)?;
+    check_quota(&user, &coordinator).await?;
+    let entry = BatchEntry {
+        url,
+        options: Default::default(),
+    };
+    call!(
+        coordinator,
+        CoordinatorMsg::Enqueue,
+        user.owner(),
+        vec![entry],
+        request.duplicate
+    )
+    .map_err(|err| ErrorInternalServerError(err.to_string()))?;
+    Ok(HttpResponse::SeeOther()
+        .insert_header(("Location", client.url("/")))
+        .finish())
+}
+
+#[derive(Debug, Deserialize)]
+struct RestartRequest {
+    id: String,
+    #[serde(default)]
+    csrf_token: String,
+}
+
+/// A form that only carries a CSRF token.
+#[derive(Debug, Deserialize)]
+struct CsrfForm {
+    #[serde(default)]
+    csrf_token: String,
+}
+
+#[post("/restart_download")]
+#[tracing::instrument(level = "info", skip(coordinator))]
+async fn restart_download(
+    request: Form<RestartRequest>,
+    coordinator: Data<ActorRef<Coordinator>>,
+    user: ReqData<CurrentUser>,
+    csrf: ReqData<Csrf>,
+    client: ClientInfo,
+) -> actix_web::Result<impl Responder> {
+    csrf.check(&request.csrf_token)?;
+    let download = manageable_download(&coordinator, &user, request.into_inner().id).await?;
+    call!(coordinator, CoordinatorMsg::Retry, download.id)
+        .map_err(|err| ErrorInternalServerError(err.to_string()))?;
+    // Restart requests come from the list page iframe, so we need to redirect to the list page.
+    Ok(HttpResponse::SeeOther()
+        .insert_header(("Location", client.url("/list")))
+        .finish())
+}
+
+/// Uploaded URL lists larger than this are rejected.
+const MAX_BATCH_SIZE: usize = 1024 * 1024;
+
+#[post("/request_batch")]
+#[tracing::instrument(level = "info", skip(payload, coordinator))]
+async fn request_batch(
+    mut payload: Multipart,
+    coordinator: Data<ActorRef<Coordinator>>,
+    user: ReqData<CurrentUser>,
+    csrf: ReqData<Csrf>,
+    client: ClientInfo,
+) -> actix_web::Result<impl Responder> {
+    check_quota(&user, &coordinator).await?;
+    // The URLs can be typed into the text box, uploaded as a file, or both.
+    let mut input = String::new();
+    let mut duplicate = None;
+    let mut extract = None;
+    let mut csrf_token = String::new();
+    while let Some(field) = payload.next().await {
+        let mut field = field?;
+        let name = field.name().map(|name| name.to_string());
+        if !matches!(
+            name.as_deref(),
+            Some("urls" | "file" | "duplicate" | "extract" | "csrf_token")
+        ) {
+            continue;
+        }
+        let mut bytes = Vec::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk?;
+            if input.len() + bytes.len() + chunk.len() > MAX_BATCH_SIZE {
+                return Err(ErrorPayloadTooLarge("The list of URLs is too large"));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        let text = std::str::from_utf8(&bytes)
+            .map_err(|_| ErrorBadRequest("The list of URLs must be UTF-8 text"))?;
+        if name.as_deref() == Some("csrf_token") {
+            csrf_token = text.to_string();
+        } else if name.as_deref() == Some("duplicate") {
+            // Left empty to use the default policy.
+            if !text.is_empty() {
+                duplicate = Some(text.parse::<DuplicatePolicy>().map_err(ErrorBadRequest)?);
+            }
+        } else if name.as_deref() == Some("extract") {
+            // Left empty to go by the kind of archive.
+            extract = match text {
+                "" => None,
+                "true" => Some(true),
+                "false" => Some(false),
+                _ => return Err(ErrorBadRequest("extract must be true or false")),
+            };
+        } else {
+            input.push_str(text);
+            input.push('\n');
+        }
+    }
+    csrf.check(&csrf_token)?;
+
+    let mut entries = Vec::new();
+    let mut invalid = Vec::new();
+    for entry in input_file::parse(&input) {
+        match entry {
+            Ok(mut entry) => {
+                // Options in the list win over the form.
+                entry.options.extract = entry.options.extract.or(extract);
+                entries.push(entry);
+            }
+            Err(entry) => invalid.push(entry),
+        }
+    }
+    let urls = entries.iter().map(|e| e.url.clone()).collect::<Vec<_>>();
+    debug!("Requesting download of {} files", urls.len());
+
+    let results = call!(
+        coordinator,
+        CoordinatorMsg::Enqueue,
+        user.owner(),
+        entries,
+        duplicate
+    )
+    .map_err(|err| ErrorInternalServerError(err.to_string()))?;
+    let mut accepted = Vec::new();
+    let mut duplicates = Vec::new();
+    let mut attached = Vec::new();
+    for (url, result) in urls.into_iter().zip(results) {
+        match result {
+            Enqueued::Accepted(_) => accepted.push(url),
+            Enqueued::Duplicate(_) => duplicates.push(url),
+            Enqueued::Attached(_) => attached.push(url),
+        }
+    }
+
+    let response = BatchSummaryTemplate {
+        base: client.base_path.clone(),
+        accepted,
+        duplicates,
+        attached,
+        invalid,
+    }
+    .render()
+    .unwrap();
+    Ok(HttpResponse::Ok().content_type("text/html").body(response))
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    q: Option<String>,
+}
+
+#[get("/history")]
+#[tracing::instrument(level = "debug", skip(history))]
+async fn list_history(
+    query: Query<HistoryQuery>,
+    history: Data<HistoryStore>,
+    user: ReqData<CurrentUser>,
+    csrf: ReqData<Csrf>,
+    client: ClientInfo,
+) -> impl Responder {
+    let query = query.into_inner().q.unwrap_or_default();
+    let needle = query.to_lowercase();
+    let mut entries = history
+        .scan()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(_, entry)| entry)
+        .filter(|entry| user.can_see(&entry.owner))
+        .filter(|entry| {
+            entry.url.to_lowercase().contains(&needle)
+                || entry.path.to_lowercase().contains(&needle)
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.completed_at));
+
+    let response = HistoryTemplate {
+        base: client.base_path.clone(),
+        query,
+        entries: entries.into_iter().map(|entry| entry.into()).collect(),
+        csrf_token: csrf.token().to_string(),
+    }
+    .render()
+    .unwrap();
+    HttpResponse::Ok().content_type("text/html").body(response)
+}
+
+#[post("/history/redownload")]
+#[tracing::instrument(level = "info", skip(history, coordinator))]
+async fn redownload(
+    request: Form<RestartRequest>,
+    history: Data<HistoryStore>,
+    coordinator: Data<ActorRef<Coordinator>>,
+    user: ReqData<CurrentUser>,
+    csrf: ReqData<Csrf>,
+    client: ClientInfo,
+) -> actix_web::Result<impl Responder> {
+    csrf.check(&request.csrf_token)?;
+    let entry = history
+        .get(&request.id)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .filter(|entry| user.can_see(&entry.owner))
+        .ok_or_else(|| ErrorNotFound("No such download in the history"))?;
+    check_quota(&user, &coordinator).await?;
+    let entry = BatchEntry {
+        url: entry.url,
+        options: entry.options,
+    };
+    call!(
+        coordinator,
+        CoordinatorMsg::Enqueue,
+        user.owner(),
+        vec![entry],
+        None
+    )
+    .map_err(|err| ErrorInternalServerError(err.to_string()))?;
+    Ok(HttpResponse::SeeOther()
+        .insert_header(("Location", client.url("/")))
+        .finish())
+}
+
+#[post("/history/clear")]
+#[tracing::instrument(level = "info", skip(history))]
+async fn clear_history(
+    request: Form<CsrfForm>,
+    history: Data<HistoryStore>,
+    user: ReqData<CurrentUser>,
+    csrf: ReqData<Csrf>,
+    client: ClientInfo,
+) -> actix_web::Result<impl Responder> {
+    csrf.check(&request.csrf_token)?;
+    let entries = history.scan().await.map_err(ErrorInternalServerError)?;
+    for (id, entry) in entries {
+        if user.can_manage(&entry.owner) {
+            history
+                .delete(&id)
+                .await
+                .map_err(ErrorInternalServerError)?;
+        }
+    }
+    Ok(HttpResponse::SeeOther()
+        .insert_header(("Location", client.url("/history")))
+        .finish())
+}
+
+async fn render_tokens(
+    tokens: &TokenStore,
+    user: &CurrentUser,
+    csrf: &Csrf,
+    client: &ClientInfo,
+    created: Option<String>,
+) -> actix_web::Result<HttpResponse> {
+    let mut tokens = tokens.scan().await.map_err(ErrorInternalServerError)?;
+    tokens.retain(|(_, token)| token.owner == user.username);
+    tokens.sort_by_key(|(_, token)| token.created_at);
+    let response = TokensTemplate {
+        base: client.base_path.clone(),
+        tokens: tokens.into_iter().map(|(_, token)| token.into()).collect(),
+        created,
+        csrf_token: csrf.token().to_string(),
+    }
+    .render()
+    .unwrap();
+    Ok(HttpResponse::Ok().content_type("text/html").body(response))
+}
+
+#[get("/tokens")]
+#[tracing::instrument(level = "debug", skip(tokens))]
+async fn list_tokens(
+    tokens: Data<TokenStore>,
+    user: ReqData<CurrentUser>,
+    csrf: ReqData<Csrf>,
+    client: ClientInfo,
+) -> actix_web::Result<impl Responder> {
+    render_tokens(&tokens, &user, &csrf, &client, None).await
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateTokenRequest {
+    name: String,
+    scope: TokenScope,
+    /// Days until the token expires, or empty if it never does.
+    expires_in_days: String,
+    #[serde(default)]
+    csrf_token: String,
+}
+
+#[post("/tokens")]
+#[tracing::instrument(level = "info", skip(tokens))]
+async fn create_token(
+    request: Form<CreateTokenRequest>,
+    tokens: Data<TokenStore>,
+    user: ReqData<CurrentUser>,
+    csrf: ReqData<Csrf>,
+    client: ClientInfo,
+) -> actix_web::Result<impl Responder> {
+    csrf.check(&request.csrf_token)?;
+    let request = request.into_inner();
+    let name = request.name.trim();
+    if name.is_empty() {
+        return Err(ErrorBadRequest("Give the token a name"));
+    }
+    let expires_at = match request.expires_in_days.trim() {
+        "" => None,
+        days => {
+            let days: u64 = days
+                .parse()
+                .map_err(|_| ErrorBadRequest("Expiry must be a number of days"))?;
+            Some(store::unix_now() + days * 24 * 60 * 60)
+        }
+    };
+    let (token, secret) = tokens::generate(
+        user.username.clone(),
+        name.to_string(),
+        request.scope,
+        expires_at,
+    );
+    tokens
+        .put(&token.id, &token)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    render_tokens(&tokens, &user, &csrf, &client, Some(secret)).await
+}
+
+#[post("/tokens/revoke")]
+#[tracing::instrument(level = "info", skip(tokens))]
+async fn revoke_token(
+    request: Form<RestartRequest>,
+    tokens: Data<TokenStore>,
+    user: ReqData<CurrentUser>,
+    csrf: ReqData<Csrf>,
+    client: ClientInfo,
+) -> actix_web::Result<impl Responder> {
+    csrf.check(&request.csrf_token)?;
+    let token = tokens
+        .get(&request.id)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .filter(|token| token.owner == user.username)
+        .ok_or_else(|| ErrorNotFound("No such token"))?;
+    tokens
+        .delete(&token.id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::SeeOther()
+        .insert_header(("Location", client.url("/tokens")))
+        .finish())
+}
+
+const GIB: u64 = 1024 * 1024 * 1024;
+
+/// Parse a quota entered in GiB. Empty means no quota.
+fn parse_quota(quota: &str) -> actix_web::Result<Option<u64>> {
+    match quota.trim() {
+        "" => Ok(None),
+        quota => {
+            let gib: f64 = quota
+                .parse()
+                .ok()
+                .filter(|gib: &f64| gib.is_finite() && *gib >= 0.0)
+                .ok_or_else(|| ErrorBadRequest("Quota must be a number of GiB"))?;
+            Ok(Some((gib * GIB as f64) as u64))
+        }
+    }
+}
+
+#[get("/users")]
+#[tracing::instrument(level = "debug", skip(users, admin))]
+async fn list_users(
+    users: Data<UserStore>,
+    admin: Data<AdminAccount>,
+    csrf: ReqData<Csrf>,
+    client: ClientInfo,
+) -> actix_web::Result<impl Responder> {
+    let mut users = users.scan().await.map_err(ErrorInternalServerError)?;
+    users.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let response = UsersTemplate {
+        base: client.base_path.clone(),
+        admin_username: admin.username.clone(),
+        users: users.into_iter().map(|(_, user)| user.into()).collect(),
+        csrf_token: csrf.token().to_string(),
+    }
+    .render()
+    .unwrap();
+    Ok(HttpResponse::Ok().content_type("text/html").body(response))
+}
+
+#[get("/webhooks")]
+#[tracing::instrument(level = "debug", skip(webhooks))]
+async fn list_webhooks(webhooks: Data<Webhooks>, client: ClientInfo) -> impl Responder {
+    let response = WebhooksTemplate {
+        base: client.base_path.clone(),
+        webhooks: webhooks.list().into_iter().map(Into::into).collect(),
+        deliveries: webhooks
+            .recent_deliveries()
+            .into_iter()
+            .map(Into::into)
+            .collect(),
+    }
+    .render()
+    .unwrap();
+    HttpResponse::Ok().content_type("text/html").body(response)
+}
+
+#[derive(Deserialize)]
+struct CreateUserRequest {
+    username: String,
+    password: String,
+    role: Role,
+    /// In GiB, empty if there is no quota.
+    quota: String,
+    #[serde(default)]
+    csrf_token: String,
+}
+
+impl std::fmt::Debug for CreateUserRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CreateUserRequest")
+            .field("username", &self.username)
+            .field("role", &self.role)
+            .field("quota", &self.quota)
+            .finish_non_exhaustive()
+    }
+}
+
+#[post("/users")]
+#[tracing::instrument(level = "info", skip(users, admin))]
+async fn create_user(
+    request: Form<CreateUserRequest>,
+    users: Data<UserStore>,
+    admin: Data<AdminAccount>,
+    csrf: ReqData<Csrf>,
+    client: ClientInfo,
+) -> actix_web::Result<impl Responder> {
+    csrf.check(&request.csrf_token)?;
+    let request = request.into_inner();
+    let username = request.username.trim();
+    users::validate_username(username).map_err(ErrorBadRequest)?;
+    if username == admin.username
+        || users
+            .get(username)
+            .await
+            .map_err(ErrorInternalServerError)?
+            .is_some()
+    {
+        return Err(ErrorBadRequest(format!("User {} already exists", username)));
+    }
+    if request.password.is_empty() {
+        return Err(ErrorBadRequest("Give the user a password"));
+    }
+    let user = User {
+        username: username.to_string(),
+        role: request.role,
+        password_hash: users::hash_password(&request.password).map_err(ErrorInternalServerError)?,
+        quota: parse_quota(&request.quota)?,
+        created_at: store::unix_now(),
+    };
+    users
+        .put(&user.username, &user)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::SeeOther()
+        .insert_header(("Location", client.url("/users")))
+        .finish())
+}
+
+#[derive(Deserialize)]
+struct UpdateUserRequest {
+    username: String,
+    role: Role,
+    quota: String,
+    /// Left empty to keep the current password.
+    password: String,
+    #[serde(default)]
+    csrf_token: String,
+}
+
+impl std::fmt::Debug for UpdateUserRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UpdateUserRequest")
+            .field("username", &self.username)
+            .field("role", &self.role)
+            .field("quota", &self.quota)
+            .finish_non_exhaustive()
+    }
+}
+
+#[post("/users/update")]
+#[tracing::instrument(level = "info", skip(users))]
+async fn update_user(
+    request: Form<UpdateUserRequest>,
+    users: Data<UserStore>,
+    csrf: ReqData<Csrf>,
+    client: ClientInfo,
+) -> actix_web::Result<impl Responder> {
+    csrf.check(&request.csrf_token)?;
+    let request = request.into_inner();
+    let mut user = users
+        .get(&request.username)
         .await
+        .map_err(ErrorInternalServerError)?
+        .ok_or_else(|| ErrorNotFound("No such user"))?;
+    user.role = request.role;
+    user.quota = parse_quota(&request.quota)?;
+    if !request.password.is_empty() {
+        user.password_hash =
+            users::hash_password(&request.password).map_err(ErrorInternalServerError)?;
+    }
+    users
+        .put(&user.username, &user)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::SeeOther()
+        .insert_header(("Location", client.url("/users")))
+        .finish())
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteUserRequest {
+    username: String,
+    #[serde(default)]
+    csrf_token: String,
+}
+
+/// Deleting a user keeps their files and downloads, so nothing is lost by
+/// accident. Their API tokens stop working.
+#[post("/users/delete")]
+#[tracing::instrument(level = "info", skip(users))]
+async fn delete_user(
+    request: Form<DeleteUserRequest>,
+    users: Data<UserStore>,
+    csrf: ReqData<Csrf>,
+    client: ClientInfo,
+) -> actix_web::Result<impl Responder> {
+    csrf.check(&request.csrf_token)?;
+    users
+        .delete(&request.username)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::SeeOther()
+        .insert_header(("Location", client.url("/users")))
+        .finish())
+}
+
+#[get("/login")]
+#[tracing::instrument(level = "debug")]
+async fn login_page(login: Data<LoginConfig>, client: ClientInfo) -> impl Responder {
+    // With Basic authentication, the browser already logged in to get here.
+    if login.mode == LoginMode::Basic {
+        return HttpResponse::SeeOther()
+            .insert_header(("Location", client.url("/")))
+            .finish();
+    }
+    let response = LoginTemplate {
+        base: client.base_path.clone(),
+        error: None,
+    }
+    .render()
+    .unwrap();
+    HttpResponse::Ok().content_type("text/html").body(response)
+}
+
+#[derive(Deserialize)]
+struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+impl std::fmt::Debug for LoginRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LoginRequest")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+#[post("/login")]
+#[tracing::instrument(level = "info", skip(users, sessions, throttle, admin))]
+async fn submit_login(
+    request: Form<LoginRequest>,
+    users: Data<UserStore>,
+    sessions: Data<SessionStore>,
+    throttle: Data<LoginThrottle>,
+    login: Data<LoginConfig>,
+    admin: Data<AdminAccount>,
+    client: ClientInfo,
+) -> actix_web::Result<impl Responder> {
+    if login.mode == LoginMode::Basic {
+        return Ok(HttpResponse::SeeOther()
+            .insert_header(("Location", client.url("/")))
+            .finish());
+    }
+    let request = request.into_inner();
+    let ip = client.ip;
+    let Some(user) = log_in(
+        &admin,
+        Some(&users),
+        &throttle,
+        None,
+        ip,
+        &request.username,
+        request.password,
+    )
+    .await?
+    else {
+        let response = LoginTemplate {
+            base: client.base_path.clone(),
+            error: Some("Wrong username or password".to_string()),
+        }
+        .render()
         .unwrap();
-    let msg = CoordinatorMsg::StartDownload(StartDownload {
-        url: request.url.clone(),
-    });
-    cast!(coordinator, msg).unwrap();
-    HttpResponse::SeeOther()
-        .insert_header((
-            "Location",
-            // Restart requests come from the list page iframe, so we need to redirect to the list page.
-            if request.restarting.unwrap_or(false) {
-                "/list"
-            } else {
-                "/"
-            },
-        ))
-        .finish()
+        return Ok(HttpResponse::Unauthorized()
+            .content_type("text/html")
+            .body(response));
+    };
+    let secret = sessions::create(&sessions, &user.username, login.session_lifetime)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!("{} logged in from {:?}", user.username, ip);
+    Ok(HttpResponse::SeeOther()
+        .cookie(sessions::cookie(secret, login.session_lifetime, &client))
+        .insert_header(("Location", client.url("/")))
+        .finish())
+}
+
+#[post("/logout")]
+#[tracing::instrument(level = "info", skip(req, sessions))]
+async fn logout(
+    req: HttpRequest,
+    request: Form<CsrfForm>,
+    sessions: Data<SessionStore>,
+    csrf: ReqData<Csrf>,
+    client: ClientInfo,
+) -> actix_web::Result<impl Responder> {
+    csrf.check(&request.csrf_token)?;
+    if let Some(cookie) = req.cookie(sessions::COOKIE) {
+        sessions::delete(&sessions, cookie.value())
+            .await
+            .map_err(ErrorInternalServerError)?;
+    }
+    Ok(HttpResponse::SeeOther()
+        .cookie(sessions::removal_cookie(&client))
+        .insert_header(("Location", client.url("/login")))
+        .finish())
 }
 
 #[derive(RustEmbed)]
 #[folder = "dist/"]
 struct Dist;
 
+async fn base_redirect(client: ClientInfo) -> HttpResponse {
+    HttpResponse::PermanentRedirect()
+        .insert_header(("Location", client.url("/")))
+        .finish()
+}
+
 #[get("/output.css")]
 #[tracing::instrument(level = "debug")]
 async fn serve_css() -> impl Responder {
     Dist::get("output.css").into_response()
 }
 
-lazy_static! {
-    static ref PASS_HASH: Option<PasswordHash<'static>> = {
-        env::var("HTTP_DROGUE_PASSWORD").ok().and_then(|password| {
-            // Yes, these parameters are deliberately weak. Because we don't
-            // actually store the passwords, we're not concerned with the
-            // strength of the hash, it is more of a crude way to throttle
-            // authentication attempts.
-            Scrypt
-                .hash_password_customized(
-                    password.as_bytes(),
-                    None,
-                    None,
-                    Params::new(1, 8, 1).unwrap(),
-                    Salt::new("salt").unwrap(),
-                )
-                .ok()
-        })
+/// Read a password from stdin and print its hash, for use in
+/// `HTTP_DROGUE_PASSWORD_HASH`.
+fn print_password_hash() -> ! {
+    let mut password = String::new();
+    if let Err(err) = io::stdin().lock().read_line(&mut password) {
+        eprintln!("Failed to read the password: {}", err);
+        exit(1);
+    }
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        eprintln!("The password can't be empty");
+        exit(1);
+    }
+    match users::hash_password(password) {
+        Ok(hash) => {
+            println!("{}", hash);
+            exit(0);
+        }
+        Err(err) => {
+            eprintln!("Failed to hash the password: {}", err);
+            exit(1);
+        }
+    }
+}
+
+/// Turn the client away until its lockout ends.
+fn locked_out(wait: Duration) -> actix_web::Error {
+    let seconds = wait.as_secs().max(1);
+    let response = HttpResponse::TooManyRequests()
+        .insert_header(("Retry-After", seconds.to_string()))
+        .body(format!(
+            "Too many failed logins, try again in {} seconds",
+            seconds
+        ));
+    InternalError::from_response("Too many failed logins", response).into()
+}
+
+/// Find the account for a username, along with its password hash. The admin
+/// account from the configuration takes precedence over stored accounts.
+async fn find_user(
+    admin: &AdminAccount,
+    users: Option<&UserStore>,
+    username: &str,
+) -> Option<(CurrentUser, String)> {
+    if username == admin.username {
+        let hash = admin.password_hash.read().unwrap().clone();
+        return Some((CurrentUser::admin(username), hash));
+    }
+    let user = users?.get(username).await.ok()??;
+    Some(((&user).into(), user.password_hash))
+}
+
+/// Check a username and password, for both Basic authentication and the login
+/// page. Returns `None` if they are wrong, and an error if the client is locked
+/// out after too many failed logins. Basic authentication checks the password
+/// on every request, so it passes a cache of the passwords checked recently.
+async fn log_in(
+    admin: &AdminAccount,
+    users: Option<&UserStore>,
+    throttle: &LoginThrottle,
+    passwords: Option<&PasswordCache>,
+    ip: Option<IpAddr>,
+    username: &str,
+    password: String,
+) -> actix_web::Result<Option<CurrentUser>> {
+    if let Some(wait) = throttle.check(ip, Some(username)) {
+        warn!(
+            "Refused login as {} from {:?}, locked out after too many failures",
+            username, ip
+        );
+        return Err(locked_out(wait));
+    }
+    let verified = match find_user(admin, users, username).await {
+        Some((user, hash)) => match passwords {
+            Some(passwords) => passwords.verify(username, password, hash).await,
+            None => users::verify_password(password, hash).await,
+        }
+        .then_some(user),
+        None => {
+            users::verify_dummy_password(password).await;
+            None
+        }
+    };
+    match &verified {
+        Some(user) => throttle.record_success(&user.username),
+        None => {
+            warn!("Failed login as {} from {:?}", username, ip);
+            throttle.record_failure(ip, Some(username));
+        }
+    }
+    Ok(verified)
+}
+
+/// Pages that can be seen without logging in, when using the login page.
+fn is_public(path: &str) -> bool {
+    matches!(path, "/login" | "/output.css")
+}
+
+/// Pages that don't need an account at all, for health checks and monitoring.
+/// Metrics have a token of their own. The service worker has nothing private
+/// in it, and browsers may fetch it without credentials.
+fn skips_login(path: &str) -> bool {
+    matches!(path, "/healthz" | "/readyz" | "/metrics" | "/sw.js")
+}
+
+/// Let an authenticated request through, as long as the user is allowed to
+/// make it.
+fn let_in(
+    req: ServiceRequest,
+    path: &str,
+    user: CurrentUser,
+    csrf: Csrf,
+) -> Result<ServiceRequest, (actix_web::Error, ServiceRequest)> {
+    if !user.may_request(req.method(), path, None) {
+        return Err((ErrorForbidden("You are not allowed to do that"), req));
+    }
+    // The API takes JSON rather than forms, so it has no CSRF tokens. Browsers
+    // say where a request comes from, which is enough to refuse other sites.
+    let cross_site = req
+        .headers()
+        .get("Sec-Fetch-Site")
+        .is_some_and(|site| site == "cross-site");
+    if cross_site && !matches!(*req.method(), Method::GET | Method::HEAD) {
+        return Err((
+            ErrorForbidden("Requests from other sites are not allowed"),
+            req,
+        ));
+    }
+    // Browsers that don't send `Sec-Fetch-Site` can still be made to submit a
+    // form to the API, but not with a JSON body or a header of our choosing,
+    // as that needs the other site to be allowed by CORS first.
+    let writes = !matches!(*req.method(), Method::GET | Method::HEAD);
+    if writes && path.starts_with("/api/") && !is_json(&req) {
+        let token = req
+            .headers()
+            .get(CSRF_HEADER)
+            .and_then(|token| token.to_str().ok())
+            .unwrap_or("");
+        if csrf.check(token).is_err() {
+            return Err((
+                ErrorForbidden("Send JSON, or the CSRF token in an X-CSRF-Token header"),
+                req,
+            ));
+        }
+    }
+    req.extensions_mut().insert(user);
+    req.extensions_mut().insert(csrf);
+    Ok(req)
+}
+
+/// Header that API requests made with a password or session cookie can carry
+/// the CSRF token in, if they don't send JSON.
+const CSRF_HEADER: &str = "X-CSRF-Token";
+
+fn is_json(req: &ServiceRequest) -> bool {
+    req.mime_type()
+        .ok()
+        .flatten()
+        .is_some_and(|mime| mime.essence_str() == "application/json")
+}
+
+/// Let the request through if it has the right username and password, an API
+/// token or a session cookie, and the user is allowed to make it. Clients with
+/// too many failed logins are locked out for a while.
+async fn authenticate(
+    req: ServiceRequest,
+    // Not `Either`, since that reads the whole request body to try both.
+    credentials: (Option<BasicAuth>, Option<BearerAuth>),
+) -> Result<ServiceRequest, (actix_web::Error, ServiceRequest)> {
+    let throttle = req
+        .app_data::<Data<LoginThrottle>>()
+        .cloned()
+        .unwrap_or_else(|| Data::new(LoginThrottle::default()));
+    let passwords = req
+        .app_data::<Data<PasswordCache>>()
+        .cloned()
+        .unwrap_or_else(|| Data::new(PasswordCache::default()));
+    let Some(csrf_key) = req.app_data::<Data<CsrfKey>>().cloned() else {
+        return Err((
+            ErrorInternalServerError("CSRF protection is not set up"),
+            req,
+        ));
+    };
+    let Some(admin) = req.app_data::<Data<AdminAccount>>().cloned() else {
+        return Err((
+            ErrorInternalServerError("The admin account is not set up"),
+            req,
+        ));
     };
+    let login = req
+        .app_data::<Data<LoginConfig>>()
+        .map(|config| *config.get_ref())
+        .unwrap_or_default();
+    let users = req.app_data::<Data<UserStore>>().cloned();
+    let users = users.as_ref().map(|users| users.get_ref());
+    let proxy = req
+        .app_data::<Data<ProxyConfig>>()
+        .map(|config| config.get_ref().clone())
+        .unwrap_or_default();
+    let client = proxy.client(req.request());
+    let ip = client.ip;
+    // Routes are checked without the base path the app is served under.
+    let path = proxy.app_path(req.path()).to_string();
+    if skips_login(&path) {
+        return Ok(req);
+    }
+    match credentials {
+        (Some(credentials), _) => {
+            let username = credentials.user_id().to_string();
+            let password = credentials.password().unwrap_or("").to_string();
+            match log_in(
+                &admin,
+                users,
+                &throttle,
+                Some(&passwords),
+                ip,
+                &username,
+                password,
+            )
+            .await
+            {
+                Ok(Some(user)) => {
+                    let csrf = csrf_key.token_for(&format!("user:{}", user.username));
+                    return let_in(req, &path, user, csrf);
+                }
+                Ok(None) => {}
+                Err(err) => return Err((err, req)),
+            }
+        }
+        (None, Some(credentials)) => {
+            if let Some(wait) = throttle.check(ip, None) {
+                warn!(
+                    "Refused token from {:?}, locked out after too many failures",
+                    ip
+                );
+                return Err((locked_out(wait), req));
+            }
+            let token = match req.app_data::<Data<TokenStore>>() {
+                Some(store) => tokens::verify(store, credentials.token()).await,
+                None => None,
+            };
+            // Tokens stop working when their owner is deleted.
+            let user = match &token {
+                Some(token) => find_user(&admin, users, &token.owner).await,
+                None => None,
+            };
+            let config = req
+                .app_data::<bearer::Config>()
+                .cloned()
+                .unwrap_or_default()
+                .realm("Http Drogue");
+            let (Some(token), Some((user, _))) = (token, user) else {
+                warn!("Failed login with an invalid token from {:?}", ip);
+                throttle.record_failure(ip, None);
+                let error =
+                    AuthenticationError::from(config).with_error(bearer::Error::InvalidToken);
+                return Err((error.into(), req));
+            };
+            if !user.may_request(req.method(), &path, Some(token.scope)) {
+                let error =
+                    AuthenticationError::from(config).with_error(bearer::Error::InsufficientScope);
+                return Err((error.into(), req));
+            }
+            req.extensions_mut().insert(user);
+            req.extensions_mut().insert(Csrf::NotNeeded);
+            return Ok(req);
+        }
+        (None, None) if login.mode == LoginMode::Form => {
+            let session = match (
+                req.cookie(sessions::COOKIE),
+                req.app_data::<Data<SessionStore>>(),
+            ) {
+                (Some(cookie), Some(store)) => sessions::find(store, cookie.value()).await,
+                _ => None,
+            };
+            // Sessions end when their user is deleted.
+            let user = match &session {
+                Some((_, session)) => find_user(&admin, users, &session.username).await,
+                None => None,
+            };
+            if let (Some((key, _)), Some((user, _))) = (session, user) {
+                let csrf = csrf_key.token_for(&format!("session:{}", key));
+                return let_in(req, &path, user, csrf);
+            }
+            if is_public(&path) {
+                return Ok(req);
+            }
+            // Send people to the login page, but not scripts that expect the
+            // request to either work or fail.
+            let error = if req.method() == Method::GET && !path.starts_with("/api/") {
+                let response = HttpResponse::SeeOther()
+                    .insert_header(("Location", client.url("/login")))
+                    .finish();
+                InternalError::from_response("Not logged in", response).into()
+            } else {
+                ErrorUnauthorized("Not logged in")
+            };
+            return Err((error, req));
+        }
+        (None, None) => {}
+    }
+
+    let config = req
+        .app_data::<basic::Config>()
+        .cloned()
+        .unwrap_or_default()
+        .realm("Http Drogue");
+    Err((AuthenticationError::from(config).into(), req))
+}
+
+/// Everything that reloading the configuration changes while running.
+#[cfg(unix)]
+struct Reloadable {
+    /// The settings we started with, which the others are compared to.
+    started: Settings,
+    admin: Data<AdminAccount>,
+    throttle: Data<LoginThrottle>,
+    proxy: ProxyConfig,
+    metrics_auth: Data<MetricsAuth>,
+    webhooks: Webhooks,
+    notifier: Notifier,
+    push: Push,
+    coordinator: ActorRef<Coordinator>,
+}
+
+#[cfg(unix)]
+impl Reloadable {
+    /// Read the configuration again, and apply the settings that can change
+    /// while running.
+    fn reload(&self, cli: &Cli) -> Result<(), String> {
+        let config = Config::load(cli)?;
+        let settings = config.validate()?;
+        for key in settings.needs_restart(&self.started) {
+            warn!("{} changed, restart to use the new value", key);
+        }
+        *self.admin.password_hash.write().unwrap() = settings.admin_password_hash;
+        self.throttle.set_limits(settings.throttle);
+        *self.metrics_auth.token.write().unwrap() = settings.metrics_token;
+        self.proxy
+            .set_trusted(settings.proxy.trusted.read().unwrap().clone());
+        self.webhooks.set(settings.webhooks);
+        self.notifier.set(settings.email);
+        self.push.set(settings.push);
+        self.coordinator
+            .cast(CoordinatorMsg::Reconfigure(settings.downloads))
+            .map_err(|err| err.to_string())?;
+        Ok(())
+    }
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let cli = Cli::parse();
+    if let Some(Command::HashPassword) = cli.command {
+        print_password_hash();
+    }
+
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_span_events(FmtSpan::NEW | FmtSpan::CLOSE))
+        .with(tracing_subscriber::EnvFilter::from_default_env())
+        .init();
+
+    let config = Config::load(&cli).unwrap_or_else(|err| {
+        error!("{}", err);
+        exit(1);
+    });
+    let settings = config.validate().unwrap_or_else(|err| {
+        error!("{}", err);
+        exit(1);
+    });
+    if config.auth.password_hash.is_none() {
+        warn!("The admin password is set in plain text, consider setting HTTP_DROGUE_PASSWORD_HASH instead. Run `http-drogue hash-password` to create one.");
+    }
+    if cli.check_config {
+        if let Some(files) = &settings.tls {
+            if let Err(err) = tls::server_config(files.clone()) {
+                error!("{}", err);
+                exit(1);
+            }
+        }
+        print!("{}", config.to_redacted_toml());
+        exit(0);
+    }
+
     // We'll store the download progress in an sqlite database.
     // This way we can resume after a restart.
-    let store: DownloadProgressStore = Cuttlestore::new(
-        env::var("STORE_PATH").unwrap_or_else(|_| "sqlite:///data/http-drogue.sqlite".to_string()),
-    )
-    .await
-    .unwrap();
+    let stores = Stores::open(&settings.store_path, &settings.admin_user)
+        .await
+        .unwrap();
+    let history_store: HistoryStore = stores.history;
+    let token_store: TokenStore = stores.tokens;
+    let user_store: UserStore = stores.users;
+    let session_store: SessionStore = stores.sessions;
+    let csrf_key = CsrfKey::load(&stores.secrets).await.unwrap();
+    let vapid_key = VapidKey::load(&stores.secrets).await.unwrap();
+    let login = settings.login;
+
+    let event_bus = EventBus::new();
+    let webhooks = Webhooks::new(settings.webhooks.clone());
+    let notifier = Notifier::new(settings.email.clone());
+    let push = Push::new(settings.push.clone(), vapid_key, stores.push_subscriptions);
 
     // The download coordinator will handle concurrently downloading files.
     let coordinator = Coordinator {
-        concurrent_downloads: 1,
-        store: store.clone(),
+        settings: settings.downloads.clone(),
+        store: stores.progress,
+        history: history_store.clone(),
+        events: event_bus.clone(),
+        webhooks: webhooks.clone(),
+        notifier: notifier.clone(),
+        push: push.clone(),
     };
     let (actor, _) = Actor::spawn(Some("coordinator".to_string()), coordinator, ())
         .await
         .unwrap();
+    notifier.spawn_digest(history_store.clone(), actor.clone());
 
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer().with_span_events(FmtSpan::NEW | FmtSpan::CLOSE))
-        .with(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
-
-    if PASS_HASH.is_none() {
-        error!("No password set, please set the HTTP_DROGUE_PASSWORD environment variable.");
-        exit(1);
+    if let Some(mqtt) = settings.mqtt.clone() {
+        // Downloads added through MQTT go to the download folder itself.
+        let owner = Owner {
+            username: settings.admin_user.clone(),
+            base_dir: String::new(),
+        };
+        mqtt::spawn(mqtt, &event_bus, actor.clone(), owner);
     }
 
-    HttpServer::new(move || {
-        let auth = HttpAuthentication::basic(|req, credentials| async move {
-            let password = credentials.password().unwrap_or("");
-            let Some(hash) = &*PASS_HASH else {
-                panic!("No password set, please set the HTTP_DROGUE_PASSWORD environment variable.");
-            };
+    let admin = Data::new(AdminAccount {
+        username: settings.admin_user.clone(),
+        password_hash: settings.admin_password_hash.clone().into(),
+    });
+    let login_throttle = Data::new(LoginThrottle::new(settings.throttle));
+    let password_cache = Data::new(PasswordCache::default());
+    users::prepare_dummy_hash();
+    let proxy = settings.proxy.clone();
+    let metrics_enabled = settings.metrics_enabled;
+    let metrics_auth = Data::new(MetricsAuth {
+        token: settings.metrics_token.clone().into(),
+    });
 
-            if Scrypt
-                .verify_password(password.as_bytes(), hash)
-                .is_ok()
-            {
-                Ok(req)
-            } else {
-                let config = req
-                    .app_data::<basic::Config>()
-                    .cloned()
-                    .unwrap_or_default()
-                    .realm("Http Drogue");
-                Err((AuthenticationError::from(config).into(), req))
+    let tls = settings.tls.clone().map(|files| {
+        tls::server_config(files).unwrap_or_else(|err| {
+            error!("{}", err);
+            exit(1);
+        })
+    });
+
+    #[cfg(unix)]
+    {
+        let reloadable = Reloadable {
+            started: settings.clone(),
+            admin: admin.clone(),
+            throttle: login_throttle.clone(),
+            proxy: proxy.clone(),
+            metrics_auth: metrics_auth.clone(),
+            webhooks: webhooks.clone(),
+            notifier,
+            push: push.clone(),
+            coordinator: actor.clone(),
+        };
+        let mut hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("Reloading the configuration");
+                if let Err(err) = reloadable.reload(&cli) {
+                    error!("Not reloading the configuration: {}", err);
+                }
             }
         });
+    }
+
+    let mut server = HttpServer::new(move || {
+        let auth = HttpAuthentication::with_fn(authenticate);
         App::new()
             .wrap(
                 DefaultHeaders::new()
@@ -278,19 +1718,89 @@ async fn main() -> std::io::Result<()> {
                     .add(("X-Frame-Options", "SAMEORIGIN"))
                     .add((
                         "Content-Security-Policy",
-                        "script-src 'unsafe-inline'; default-src 'self'",
+                        "script-src 'self' 'unsafe-inline'; default-src 'self'",
                     )),
             )
             .wrap(auth)
-            .app_data(Data::new(store.clone()))
+            .app_data(Data::new(history_store.clone()))
+            .app_data(Data::new(token_store.clone()))
+            .app_data(Data::new(user_store.clone()))
+            .app_data(admin.clone())
+            .app_data(login_throttle.clone())
+            .app_data(password_cache.clone())
+            .app_data(Data::new(session_store.clone()))
+            .app_data(Data::new(csrf_key.clone()))
+            .app_data(Data::new(login))
             .app_data(Data::new(actor.clone()))
-            .service(home)
-            .service(request_download)
-            .service(list)
-            .service(serve_css)
+            .app_data(Data::new(event_bus.clone()))
+            .app_data(Data::new(proxy.clone()))
+            .app_data(metrics_auth.clone())
+            .app_data(Data::new(webhooks.clone()))
+            .app_data(Data::new(push.clone()))
+            .configure(|cfg| {
+                // Without the trailing slash, links would miss the base path.
+                if !proxy.base_path.is_empty() {
+                    cfg.route(&proxy.base_path, web::get().to(base_redirect));
+                }
+            })
+            .service(
+                web::scope(&proxy.base_path)
+                    .service(home)
+                    .service(request_download)
+                    .service(restart_download)
+                    .service(request_batch)
+                    .service(list)
+                    .service(list_history)
+                    .service(redownload)
+                    .service(clear_history)
+                    .service(list_tokens)
+                    .service(create_token)
+                    .service(revoke_token)
+                    .service(list_users)
+                    .service(create_user)
+                    .service(update_user)
+                    .service(delete_user)
+                    .service(list_webhooks)
+                    .service(login_page)
+                    .service(submit_login)
+                    .service(logout)
+                    .service(serve_css)
+                    .service(push::subscribe)
+                    .service(push::unsubscribe)
+                    .service(push::service_worker)
+                    .service(events::events)
+                    .service(health::healthz)
+                    .service(health::readyz)
+                    .configure(|cfg| {
+                        if metrics_enabled {
+                            cfg.service(metrics::metrics);
+                        }
+                    })
+                    .configure(api::configure),
+            )
     })
-    .bind(("0.0.0.0", 8080))?
-    .workers(1)
-    .run()
-    .await
+    .workers(settings.workers);
+    for listener in &settings.listeners {
+        server = match (listener, &tls) {
+            (Listener::Tcp(addr), Some(config)) => server.bind_rustls(addr, config.clone())?,
+            (Listener::Tcp(addr), None) => server.bind(addr)?,
+            // TLS is left to whatever connects to the socket.
+            #[cfg(unix)]
+            (Listener::Unix(path), _) => {
+                listen::remove_stale_socket(path)?;
+                server.bind_uds(path)?
+            }
+            #[cfg(not(unix))]
+            (Listener::Unix(_), _) => {
+                error!("Unix sockets are not supported on this platform");
+                exit(1);
+            }
+        };
+        let scheme = match (listener, &tls) {
+            (Listener::Tcp(_), Some(_)) => "https",
+            _ => "http",
+        };
+        info!("Listening for {} on {}", scheme, listener);
+    }
+    server.run().await
 }
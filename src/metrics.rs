@@ -0,0 +1,134 @@
+//! Prometheus metrics, served at `/metrics` when enabled.
+//!
+//! The metrics are kept in the default registry. Scraping them doesn't go
+//! through the login, and is only protected by a token if one is set, so that
+//! Prometheus doesn't need the credentials of an account.
+use std::sync::RwLock;
+
+use actix_web::{get, web::Data, HttpRequest, HttpResponse, Responder};
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_gauge, register_histogram, register_histogram_vec,
+    register_int_counter, register_int_counter_vec, register_int_gauge_vec, Encoder, Gauge,
+    Histogram, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+use ractor::ActorProcessingErr;
+
+use crate::{download_actor::DownloadError, store::StoreError, tokens};
+
+lazy_static! {
+    /// Downloads that haven't completed, by state.
+    pub static ref DOWNLOADS: IntGaugeVec = register_int_gauge_vec!(
+        "http_drogue_downloads",
+        "Downloads that haven't completed, by state.",
+        &["state"]
+    )
+    .unwrap();
+    pub static ref DOWNLOADED_BYTES: IntCounter = register_int_counter!(
+        "http_drogue_downloaded_bytes_total",
+        "Bytes downloaded."
+    )
+    .unwrap();
+    pub static ref DOWNLOADED_BYTES_BY_HOST: IntCounterVec = register_int_counter_vec!(
+        "http_drogue_host_downloaded_bytes_total",
+        "Bytes downloaded from each host.",
+        &["host"]
+    )
+    .unwrap();
+    pub static ref THROUGHPUT: Gauge = register_gauge!(
+        "http_drogue_download_speed_bytes_per_second",
+        "Bytes per second of all running downloads together."
+    )
+    .unwrap();
+    pub static ref RETRIES: IntCounterVec = register_int_counter_vec!(
+        "http_drogue_download_retries_total",
+        "Downloads restarted after failing, by the kind of error.",
+        &["error"]
+    )
+    .unwrap();
+    /// From 1 second to about 18 hours.
+    pub static ref COMPLETION_DURATION: Histogram = register_histogram!(
+        "http_drogue_download_duration_seconds",
+        "How long completed downloads took, including retries.",
+        exponential_buckets(1.0, 4.0, 9).unwrap()
+    )
+    .unwrap();
+    pub static ref STORE_LATENCY: HistogramVec = register_histogram_vec!(
+        "http_drogue_store_operation_duration_seconds",
+        "How long reading from and writing to the store takes.",
+        &["operation"]
+    )
+    .unwrap();
+}
+
+/// A short name for the kind of error a download failed with.
+pub fn error_class(err: &ActorProcessingErr) -> &'static str {
+    if let Some(err) = err.downcast_ref::<DownloadError>() {
+        return match err {
+            DownloadError::NotFound(_) => "not_found",
+            DownloadError::Blocked(_) => "blocked",
+            DownloadError::Hook(_) => "hook",
+            DownloadError::Extract(..) => "extract",
+            DownloadError::NoSpace { .. } => "no_space",
+        };
+    }
+    if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+        return if err.is_timeout() {
+            "timeout"
+        } else if err.is_connect() {
+            "connect"
+        } else if err.is_status() {
+            "status"
+        } else if err.is_body() || err.is_decode() {
+            "body"
+        } else {
+            "request"
+        };
+    }
+    if err.is::<std::io::Error>() {
+        return "io";
+    }
+    if err.is::<StoreError>() {
+        return "store";
+    }
+    "other"
+}
+
+/// The token Prometheus has to send, if any. Reloading the configuration can
+/// change it.
+#[derive(Debug, Default)]
+pub struct MetricsAuth {
+    pub token: RwLock<Option<String>>,
+}
+
+impl MetricsAuth {
+    fn allows(&self, req: &HttpRequest) -> bool {
+        let token = self.token.read().unwrap();
+        let Some(token) = token.as_deref() else {
+            return true;
+        };
+        req.headers()
+            .get("Authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|sent| tokens::constant_time_eq(sent, token))
+    }
+}
+
+#[get("/metrics")]
+#[tracing::instrument(level = "debug", skip(req, auth))]
+async fn metrics(req: HttpRequest, auth: Data<MetricsAuth>) -> impl Responder {
+    if !auth.allows(&req) {
+        return HttpResponse::Unauthorized()
+            .insert_header(("WWW-Authenticate", "Bearer"))
+            .finish();
+    }
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if let Err(err) = encoder.encode(&prometheus::gather(), &mut body) {
+        return HttpResponse::InternalServerError().body(err.to_string());
+    }
+    HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(body)
+}
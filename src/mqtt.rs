@@ -0,0 +1,389 @@
+//! Publishes downloads to an MQTT broker, for Home Assistant and other home
+//! automation, and takes commands from it.
+//!
+//! Every download that hasn't completed has a retained message with its
+//! current state, the same JSON as the API returns, which is cleared once the
+//! download completes or is deleted. Every event is also published as it
+//! happens, along with the combined speed of the running downloads now and
+//! then. If turned on, commands to add, pause, resume and cancel downloads are
+//! read from a command topic, and carried out as the admin, so anyone who can
+//! publish to the broker can do anything the admin can.
+//!
+//! If the broker goes away, we keep trying to reconnect, waiting longer each
+//! time. Everything is published again once we're back, in case the broker
+//! lost its retained messages.
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use ractor::{call, ActorRef};
+use rumqttc::{AsyncClient, Event as MqttEvent, LastWill, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::broadcast::error::RecvError,
+    time::{interval, sleep_until, Instant},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    api::DownloadResponse,
+    download_actor::{Coordinator, CoordinatorMsg, Enqueued, Owner},
+    events::{Event, EventBus},
+    extract::ExtractProgress,
+    input_file::{self, BatchEntry},
+    metrics,
+    store::DownloadOptions,
+};
+
+/// How many messages can wait to be sent while the broker is unreachable.
+/// Anything more is dropped, we publish everything again after reconnecting.
+const CAPACITY: usize = 256;
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+/// How long to wait before reconnecting the first time. This doubles after
+/// every failed attempt, up to `MAX_BACKOFF`.
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct MqttSettings {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Put in front of all the topics, like `http-drogue/status`.
+    pub topic_prefix: String,
+    /// How often to publish the combined download speed.
+    pub throughput_interval: Duration,
+    /// Take commands from the command topic.
+    pub commands: bool,
+}
+
+impl std::fmt::Debug for MqttSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MqttSettings")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("client_id", &self.client_id)
+            .field("username", &self.username)
+            .field("topic_prefix", &self.topic_prefix)
+            .field("throughput_interval", &self.throughput_interval)
+            .field("commands", &self.commands)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A command read from the command topic.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum Command {
+    Add {
+        url: String,
+        out: Option<String>,
+        dir: Option<String>,
+        extract: Option<bool>,
+    },
+    Pause {
+        id: String,
+    },
+    Resume {
+        id: String,
+    },
+    Cancel {
+        id: String,
+    },
+}
+
+/// Published to the result topic after each command.
+#[derive(Debug, Serialize)]
+struct CommandResult {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+struct Publisher {
+    settings: MqttSettings,
+    client: AsyncClient,
+    coordinator: ActorRef<Coordinator>,
+    /// Who downloads added through commands belong to.
+    owner: Owner,
+    /// The downloads we have a retained message for.
+    downloads: HashMap<String, DownloadResponse>,
+}
+
+/// Start publishing in the background.
+pub fn spawn(
+    settings: MqttSettings,
+    events: &EventBus,
+    coordinator: ActorRef<Coordinator>,
+    owner: Owner,
+) {
+    let mut options = MqttOptions::new(&settings.client_id, &settings.host, settings.port);
+    options.set_keep_alive(KEEP_ALIVE);
+    if let Some(username) = &settings.username {
+        options.set_credentials(username, settings.password.clone().unwrap_or_default());
+    }
+    options.set_last_will(LastWill::new(
+        format!("{}/status", settings.topic_prefix),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    let (client, mut eventloop) = AsyncClient::new(options, CAPACITY);
+    let mut events = events.subscribe();
+    let mut publisher = Publisher {
+        settings,
+        client,
+        coordinator,
+        owner,
+        downloads: HashMap::new(),
+    };
+
+    tokio::spawn(async move {
+        let mut throughput = interval(publisher.settings.throughput_interval);
+        let mut backoff = FIRST_BACKOFF;
+        let mut reconnect_at: Option<Instant> = None;
+        loop {
+            let now = Instant::now();
+            tokio::select! {
+                event = eventloop.poll(), if reconnect_at.is_none() => match event {
+                    Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                        info!(
+                            "Connected to the MQTT broker at {}:{}",
+                            publisher.settings.host, publisher.settings.port
+                        );
+                        backoff = FIRST_BACKOFF;
+                        publisher.connected().await;
+                    }
+                    Ok(MqttEvent::Incoming(Packet::Publish(message))) => {
+                        if publisher.settings.commands && message.topic == publisher.topic("command") {
+                            publisher.command(&message.payload).await;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        warn!(
+                            "Lost the connection to the MQTT broker, reconnecting in {} seconds: {}",
+                            backoff.as_secs(),
+                            err
+                        );
+                        reconnect_at = Some(Instant::now() + backoff);
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                    }
+                },
+                _ = sleep_until(reconnect_at.unwrap_or(now)), if reconnect_at.is_some() => {
+                    reconnect_at = None;
+                }
+                published = events.recv() => match published {
+                    Ok(published) => publisher.event(published.event).await,
+                    Err(RecvError::Lagged(missed)) => {
+                        debug!("The MQTT publisher missed {} events", missed);
+                        publisher.publish_all().await;
+                    }
+                    Err(RecvError::Closed) => return,
+                },
+                _ = throughput.tick() => {
+                    let speed = format!("{:.0}", metrics::THROUGHPUT.get());
+                    publisher.publish("throughput", true, speed);
+                }
+            }
+        }
+    });
+}
+
+impl Publisher {
+    fn topic(&self, topic: &str) -> String {
+        format!("{}/{}", self.settings.topic_prefix, topic)
+    }
+
+    /// Queue a message. If the broker is away and the queue is full, it is
+    /// dropped.
+    fn publish(&self, topic: &str, retain: bool, payload: impl Into<Vec<u8>>) {
+        let topic = self.topic(topic);
+        if let Err(err) = self
+            .client
+            .try_publish(&topic, QoS::AtLeastOnce, retain, payload)
+        {
+            debug!("Not publishing to {}: {}", topic, err);
+        }
+    }
+
+    fn publish_json(&self, topic: &str, retain: bool, value: &impl Serialize) {
+        match serde_json::to_vec(value) {
+            Ok(payload) => self.publish(topic, retain, payload),
+            Err(err) => warn!("Failed to encode the message for {}: {}", topic, err),
+        }
+    }
+
+    async fn connected(&mut self) {
+        if self.settings.commands {
+            let command = self.topic("command");
+            if let Err(err) = self.client.try_subscribe(&command, QoS::AtLeastOnce) {
+                warn!("Failed to subscribe to {}: {}", command, err);
+            }
+        }
+        self.publish("status", true, "online");
+        self.publish_all().await;
+    }
+
+    /// Publish every download, and clear the ones that are gone.
+    async fn publish_all(&mut self) {
+        let (downloads, running) = match (
+            call!(self.coordinator, CoordinatorMsg::List),
+            call!(self.coordinator, CoordinatorMsg::Running),
+        ) {
+            (Ok(downloads), Ok(running)) => (downloads, running),
+            (Err(err), _) | (_, Err(err)) => {
+                warn!("Failed to list the downloads to publish: {}", err);
+                return;
+            }
+        };
+        let running: HashSet<String> = running.into_iter().collect();
+        let previous = std::mem::take(&mut self.downloads);
+        for progress in downloads {
+            let download = DownloadResponse::new(progress, &running);
+            self.publish_json(&format!("downloads/{}", download.id), true, &download);
+            self.downloads.insert(download.id.clone(), download);
+        }
+        for id in previous.keys() {
+            if !self.downloads.contains_key(id) {
+                self.publish(&format!("downloads/{}", id), true, "");
+            }
+        }
+    }
+
+    /// Publish the current state of a download, looking it up again.
+    async fn refresh(&mut self, id: &str) {
+        let found = call!(self.coordinator, CoordinatorMsg::Get, id.to_string());
+        let running = call!(self.coordinator, CoordinatorMsg::Running);
+        let (progress, running) = match (found, running) {
+            (Ok(Some(progress)), Ok(running)) => (progress, running),
+            // Completed or deleted already, which we'll hear about.
+            (Ok(None), _) => return,
+            (Err(err), _) | (_, Err(err)) => {
+                warn!("Failed to get download {} to publish: {}", id, err);
+                return;
+            }
+        };
+        let download = DownloadResponse::new(progress, &running.into_iter().collect());
+        self.publish_json(&format!("downloads/{}", id), true, &download);
+        self.downloads.insert(id.to_string(), download);
+    }
+
+    async fn event(&mut self, event: Event) {
+        self.publish_json("events", false, &event);
+        match event {
+            Event::Added { id, .. } | Event::State { id, .. } => self.refresh(&id).await,
+            Event::Progress {
+                id,
+                progress,
+                total,
+                speed,
+            } => match self.downloads.get_mut(&id) {
+                Some(download) => {
+                    download.progress = progress;
+                    download.total = total;
+                    download.speed = speed;
+                    self.publish_json(&format!("downloads/{}", id), true, &self.downloads[&id]);
+                }
+                None => self.refresh(&id).await,
+            },
+            Event::Extracting { id, done, total } => match self.downloads.get_mut(&id) {
+                Some(download) => {
+                    download.extraction = Some(ExtractProgress { done, total });
+                    self.publish_json(&format!("downloads/{}", id), true, &self.downloads[&id]);
+                }
+                None => self.refresh(&id).await,
+            },
+            Event::Removed { id } => {
+                self.downloads.remove(&id);
+                self.publish(&format!("downloads/{}", id), true, "");
+            }
+        }
+    }
+
+    async fn command(&mut self, payload: &[u8]) {
+        let result = match serde_json::from_slice::<Command>(payload) {
+            Ok(command) => {
+                debug!("Received MQTT command {:?}", command);
+                self.run(command).await
+            }
+            Err(err) => Err(format!("Invalid command: {}", err)),
+        };
+        let result = match result {
+            Ok(id) => CommandResult {
+                ok: true,
+                id: Some(id),
+                error: None,
+            },
+            Err(error) => {
+                warn!("MQTT command failed: {}", error);
+                CommandResult {
+                    ok: false,
+                    id: None,
+                    error: Some(error),
+                }
+            }
+        };
+        self.publish_json("command/result", false, &result);
+    }
+
+    /// Carry out a command, returning the ID of the download it was about.
+    async fn run(&self, command: Command) -> Result<String, String> {
+        let (id, found) = match command {
+            Command::Add {
+                url,
+                out,
+                dir,
+                extract,
+            } => {
+                let entry = BatchEntry {
+                    url: input_file::parse_url(&url)?,
+                    options: DownloadOptions {
+                        out: out.as_deref().map(input_file::validate_out).transpose()?,
+                        dir: dir.as_deref().map(input_file::validate_dir).transpose()?,
+                        headers: Vec::new(),
+                        extract,
+                    },
+                };
+                let result = call!(
+                    self.coordinator,
+                    CoordinatorMsg::Enqueue,
+                    self.owner.clone(),
+                    vec![entry],
+                    None
+                )
+                .map_err(|err| err.to_string())?
+                .pop();
+                return match result {
+                    Some(Enqueued::Accepted(id) | Enqueued::Attached(id)) => Ok(id),
+                    Some(Enqueued::Duplicate(id)) => {
+                        Err(format!("{} is already being downloaded as {}", url, id))
+                    }
+                    None => Err("The coordinator did not respond".to_string()),
+                };
+            }
+            Command::Pause { id } => {
+                let found = call!(self.coordinator, CoordinatorMsg::Pause, id.clone());
+                (id, found)
+            }
+            Command::Resume { id } => {
+                let found = call!(self.coordinator, CoordinatorMsg::Retry, id.clone());
+                (id, found)
+            }
+            Command::Cancel { id } => {
+                let found = call!(self.coordinator, CoordinatorMsg::Delete, id.clone());
+                (id, found)
+            }
+        };
+        match found {
+            Ok(true) => Ok(id),
+            Ok(false) => Err(format!("There is no download {}", id)),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! Keeping what the server told us about a file on the downloaded copy.
+//!
+//! Completed files get the modification time from the `Last-Modified` header,
+//! rather than the time the download finished. The URL they came from, and
+//! the referrer if the download sent one, go into the `user.xdg.origin.url`
+//! and `user.xdg.referrer.url` extended attributes, like browsers on Linux do,
+//! so file managers can show where a file came from. Both are best effort: a
+//! filesystem without extended attributes, or a server without the header,
+//! only means the file goes without.
+use std::{fs::File, io, path::Path, time::SystemTime};
+
+use reqwest::{header::HeaderMap, Url};
+
+/// When the server says the file was last modified.
+pub fn last_modified(headers: &HeaderMap) -> Option<SystemTime> {
+    let value = headers.get(reqwest::header::LAST_MODIFIED)?.to_str().ok()?;
+    httpdate::parse_http_date(value).ok()
+}
+
+pub fn set_modified(path: &Path, time: SystemTime) -> io::Result<()> {
+    File::options().write(true).open(path)?.set_modified(time)
+}
+
+/// Record where the file came from. Credentials in the URLs are left out, as
+/// anyone who can read the file can read its attributes.
+pub fn set_origin(path: &Path, url: &str, referrer: Option<&str>) -> io::Result<()> {
+    set_xattr(path, "user.xdg.origin.url", &without_credentials(url))?;
+    if let Some(referrer) = referrer {
+        set_xattr(
+            path,
+            "user.xdg.referrer.url",
+            &without_credentials(referrer),
+        )?;
+    }
+    Ok(())
+}
+
+/// Whether the error means the filesystem doesn't have extended attributes.
+pub fn is_unsupported(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    if err.raw_os_error() == Some(libc::EOPNOTSUPP) {
+        return true;
+    }
+    err.kind() == io::ErrorKind::Unsupported
+}
+
+fn without_credentials(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut parsed) => {
+            let _ = parsed.set_username("");
+            let _ = parsed.set_password(None);
+            parsed.to_string()
+        }
+        Err(_) => url.to_string(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_xattr(path: &Path, name: &str, value: &str) -> io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let invalid = |err| io::Error::new(io::ErrorKind::InvalidInput, err);
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(invalid)?;
+    let name = CString::new(name).map_err(invalid)?;
+    let result = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_xattr(_path: &Path, _name: &str, _value: &str) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
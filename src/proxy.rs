@@ -0,0 +1,188 @@
+//! Running behind a reverse proxy.
+//!
+//! The app can be served under a base path like `/drogue`, which is added to
+//! the routes, links and redirects. Proxies in the trusted list can also tell
+//! us the client's address, whether it used HTTPS, and a path prefix they strip
+//! before forwarding, with the `X-Forwarded-For`, `X-Forwarded-Proto` and
+//! `X-Forwarded-Prefix` headers. The headers are ignored from anyone else,
+//! otherwise clients could get around the login throttling by making up
+//! addresses.
+use std::{
+    future::{ready, Ready},
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use actix_web::{dev::Payload, web::Data, FromRequest, HttpRequest};
+use ipnet::IpNet;
+
+/// Where a trusted proxy connects from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrustedProxy {
+    Net(IpNet),
+    /// Anything connecting through a Unix socket.
+    Unix,
+}
+
+impl FromStr for TrustedProxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "unix" {
+            return Ok(TrustedProxy::Unix);
+        }
+        if let Ok(net) = s.parse::<IpNet>() {
+            return Ok(TrustedProxy::Net(net.trunc()));
+        }
+        s.parse::<IpAddr>()
+            .map(|ip| TrustedProxy::Net(ip.into()))
+            .map_err(|_| {
+                format!(
+                    "Invalid proxy {}, expected an IP address, a CIDR range or unix",
+                    s
+                )
+            })
+    }
+}
+
+/// Parse a list of trusted proxies separated by commas or whitespace.
+pub fn parse_trusted(list: &str) -> Result<Vec<TrustedProxy>, String> {
+    list.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|proxy| !proxy.is_empty())
+        .map(|proxy| proxy.parse())
+        .collect()
+}
+
+/// Turn a base path into the form `/some/path`, or an empty string for the
+/// root. It ends up in links and headers, so only plain URL characters are
+/// allowed.
+pub fn normalize_base_path(path: &str) -> Result<String, String> {
+    let trimmed = path.trim().trim_matches('/');
+    let valid = trimmed
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '-' | '.' | '_' | '~' | '%'));
+    if !valid || trimmed.split('/').any(|part| part == "..") {
+        return Err(format!("Invalid base path {}", path));
+    }
+    if trimmed.is_empty() {
+        Ok(String::new())
+    } else {
+        Ok(format!("/{}", trimmed))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProxyConfig {
+    /// The path the app is served under, like `/drogue`. Empty for the root.
+    pub base_path: String,
+    /// Shared by all workers, so that reloading the configuration can change
+    /// it.
+    pub trusted: Arc<RwLock<Vec<TrustedProxy>>>,
+}
+
+/// Who made a request and how, as seen by the proxy if it is trusted.
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub https: bool,
+    /// Where the app is, as seen by the client. Links and redirects start
+    /// with this.
+    pub base_path: String,
+}
+
+impl ClientInfo {
+    /// A link to a page of the app.
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_path, path)
+    }
+}
+
+/// All values of a header, split at commas.
+fn header_values<'a>(req: &'a HttpRequest, name: &str) -> Vec<&'a str> {
+    req.headers()
+        .get_all(name)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|value| value.trim())
+        .collect()
+}
+
+impl ProxyConfig {
+    pub fn set_trusted(&self, trusted: Vec<TrustedProxy>) {
+        *self.trusted.write().unwrap() = trusted;
+    }
+
+    fn trusts(&self, peer: Option<IpAddr>) -> bool {
+        self.trusted
+            .read()
+            .unwrap()
+            .iter()
+            .any(|proxy| match (proxy, peer) {
+                (TrustedProxy::Net(net), Some(ip)) => net.contains(&ip),
+                (TrustedProxy::Unix, None) => true,
+                _ => false,
+            })
+    }
+
+    /// The path of a request within the app, without the base path.
+    pub fn app_path<'a>(&self, path: &'a str) -> &'a str {
+        match path.strip_prefix(self.base_path.as_str()) {
+            Some("") => "/",
+            Some(rest) if rest.starts_with('/') => rest,
+            _ => path,
+        }
+    }
+
+    pub fn client(&self, req: &HttpRequest) -> ClientInfo {
+        let peer = req.peer_addr().map(|addr| addr.ip());
+        let secure = req.app_config().secure();
+        if !self.trusts(peer) {
+            return ClientInfo {
+                ip: peer,
+                https: secure,
+                base_path: self.base_path.clone(),
+            };
+        }
+
+        // Each proxy adds the address it got the request from. The right-most
+        // address that isn't one of our proxies is the client, anything to
+        // its left could have been made up by them.
+        let mut ip = peer;
+        for forwarded in header_values(req, "X-Forwarded-For").into_iter().rev() {
+            let Ok(forwarded) = forwarded.parse::<IpAddr>() else {
+                break;
+            };
+            ip = Some(forwarded);
+            if !self.trusts(ip) {
+                break;
+            }
+        }
+        let https = match header_values(req, "X-Forwarded-Proto").first() {
+            Some(proto) => proto.eq_ignore_ascii_case("https"),
+            None => secure,
+        };
+        let prefix = header_values(req, "X-Forwarded-Prefix")
+            .first()
+            .and_then(|prefix| normalize_base_path(prefix).ok())
+            .unwrap_or_default();
+        ClientInfo {
+            ip,
+            https,
+            base_path: format!("{}{}", prefix, self.base_path),
+        }
+    }
+}
+
+impl FromRequest for ClientInfo {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let client = match req.app_data::<Data<ProxyConfig>>() {
+            Some(config) => config.client(req),
+            None => ProxyConfig::default().client(req),
+        };
+        ready(Ok(client))
+    }
+}
@@ -0,0 +1,367 @@
+//! Browser notifications through Web Push, so people hear about their
+//! downloads without keeping the page open.
+//!
+//! The page registers a service worker and subscribes through the push service
+//! of the browser, which hands it an endpoint and the keys to encrypt messages
+//! with. Each subscription belongs to the user who made it, and gets a message
+//! when one of their downloads completes or fails. Messages are signed with a
+//! VAPID key that is created on the first start, so the push service can tell
+//! they come from us. Subscriptions the push service says are gone are
+//! removed.
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use actix_web::{
+    error::{ErrorBadRequest, ErrorInternalServerError, ErrorNotFound},
+    get, post,
+    web::{Data, Json, ReqData},
+    HttpResponse, Responder,
+};
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+use web_push_native::{
+    jwt_simple::algorithms::{ECDSAP256PublicKeyLike, ES256KeyPair},
+    WebPushBuilder,
+};
+
+use crate::{
+    csrf::Csrf,
+    destination::DestinationPolicy,
+    store::{
+        unix_now, HistoryEntry, Progress, PushKeys, PushSubscription, PushSubscriptionStore,
+        SecretStore, StoreError,
+    },
+    tokens,
+    users::CurrentUser,
+    HistoryDisplay, ProgressDisplay,
+};
+
+/// How long to wait for the push service to take a message.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Shows the messages as notifications, and opens the page when one is
+/// clicked.
+const SERVICE_WORKER: &str = include_str!("sw.js");
+
+#[derive(Debug, Clone)]
+pub struct PushSettings {
+    /// Who runs the server, as a `mailto:` or `https:` URL. Push services
+    /// use it to get in touch if something is wrong with our messages.
+    pub contact: String,
+    /// Endpoints come from the browser, so they are held to the same rules
+    /// as downloads.
+    pub destinations: Arc<DestinationPolicy>,
+}
+
+/// The key push messages are signed with, kept in the store so subscriptions
+/// keep working after a restart.
+#[derive(Clone)]
+pub struct VapidKey {
+    key_pair: Arc<ES256KeyPair>,
+}
+
+impl std::fmt::Debug for VapidKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VapidKey").finish_non_exhaustive()
+    }
+}
+
+impl VapidKey {
+    /// Load the key, creating it the first time.
+    pub async fn load(secrets: &SecretStore) -> Result<Self, StoreError> {
+        let stored = secrets
+            .get("vapid")
+            .await?
+            .and_then(|pem| ES256KeyPair::from_pem(&pem).ok());
+        let key_pair = match stored {
+            Some(key_pair) => key_pair,
+            None => {
+                let key_pair = ES256KeyPair::generate();
+                let pem = key_pair
+                    .to_pem()
+                    .map_err(|err| StoreError::Store(err.to_string().into()))?;
+                secrets.put("vapid", &pem).await?;
+                key_pair
+            }
+        };
+        Ok(VapidKey {
+            key_pair: Arc::new(key_pair),
+        })
+    }
+
+    /// The public key the browser needs to subscribe, hex encoded.
+    pub fn public_key(&self) -> String {
+        self.key_pair
+            .public_key()
+            .public_key()
+            .to_bytes_uncompressed()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+/// What the service worker gets.
+#[derive(Debug, Serialize)]
+struct Message {
+    title: String,
+    body: String,
+    /// A newer notification about the same download replaces the older one.
+    tag: String,
+}
+
+#[derive(Debug)]
+struct Inner {
+    settings: RwLock<Option<PushSettings>>,
+    key: VapidKey,
+    subscriptions: PushSubscriptionStore,
+}
+
+/// Sends the push messages in the background. Cloning it is cheap.
+#[derive(Debug, Clone)]
+pub struct Push {
+    inner: Arc<Inner>,
+}
+
+impl Push {
+    pub fn new(
+        settings: Option<PushSettings>,
+        key: VapidKey,
+        subscriptions: PushSubscriptionStore,
+    ) -> Self {
+        Push {
+            inner: Arc::new(Inner {
+                settings: RwLock::new(settings),
+                key,
+                subscriptions,
+            }),
+        }
+    }
+
+    /// Replace the settings, when the configuration is reloaded.
+    pub fn set(&self, settings: Option<PushSettings>) {
+        *self.inner.settings.write().unwrap() = settings;
+    }
+
+    fn settings(&self) -> Option<PushSettings> {
+        self.inner.settings.read().unwrap().clone()
+    }
+
+    /// The public key to subscribe with, or `None` if push notifications are
+    /// turned off.
+    pub fn public_key(&self) -> Option<String> {
+        self.settings().map(|_| self.inner.key.public_key())
+    }
+
+    pub fn completed(&self, entry: &HistoryEntry) {
+        let download = HistoryDisplay::from(entry.clone());
+        let message = Message {
+            title: format!("Downloaded {}", download.path),
+            body: format!("{} in {}", download.size, download.duration),
+            tag: download.id,
+        };
+        self.send_later(entry.owner.clone(), message);
+    }
+
+    pub fn failed(&self, record: &Progress) {
+        let download = ProgressDisplay::from(record.clone());
+        let message = Message {
+            title: format!("Failed to download {}", download.name),
+            body: download.error.unwrap_or(download.url),
+            tag: download.id,
+        };
+        self.send_later(record.owner.clone(), message);
+    }
+
+    /// Send the message to the browsers of `owner` in the background.
+    /// Failures are only logged.
+    fn send_later(&self, owner: String, message: Message) {
+        let Some(settings) = self.settings() else {
+            return;
+        };
+        let push = self.clone();
+        tokio::spawn(async move {
+            if let Err(err) = push.send(&settings, &owner, &message).await {
+                warn!("Failed to send push notifications to {}: {}", owner, err);
+            }
+        });
+    }
+
+    async fn send(
+        &self,
+        settings: &PushSettings,
+        owner: &str,
+        message: &Message,
+    ) -> Result<(), String> {
+        let subscriptions: Vec<(String, PushSubscription)> = self
+            .inner
+            .subscriptions
+            .scan()
+            .await
+            .map_err(|err| err.to_string())?
+            .into_iter()
+            .filter(|(_, subscription)| subscription.owner == owner)
+            .collect();
+        if subscriptions.is_empty() {
+            return Ok(());
+        }
+        let client = settings
+            .destinations
+            .client()
+            .map_err(|err| err.to_string())?;
+        let payload = serde_json::to_vec(message).map_err(|err| err.to_string())?;
+
+        for (key, subscription) in subscriptions {
+            let request = builder(&subscription).and_then(|builder| {
+                builder
+                    .with_vapid(&self.inner.key.key_pair, &settings.contact)
+                    .build(payload.clone())
+                    .map_err(|err| err.to_string())
+            });
+            let request = match request {
+                Ok(request) => request,
+                Err(err) => {
+                    warn!("Failed to encrypt the push message: {}", err);
+                    continue;
+                }
+            };
+            let (parts, body) = request.into_parts();
+            let mut request = client
+                .post(parts.uri.to_string())
+                .timeout(TIMEOUT)
+                .body(body);
+            for (name, value) in &parts.headers {
+                request = request.header(name.as_str(), value.as_bytes());
+            }
+            match request.send().await.map(|response| response.status()) {
+                Ok(status) if status.is_success() => {
+                    debug!("Sent a push message to {}", subscription.endpoint);
+                }
+                // The browser unsubscribed, or the subscription expired.
+                Ok(StatusCode::NOT_FOUND | StatusCode::GONE) => {
+                    info!(
+                        "Removing the push subscription of {} at {}, it is gone",
+                        owner, subscription.endpoint
+                    );
+                    if let Err(err) = self.inner.subscriptions.delete(&key).await {
+                        warn!("Failed to remove the push subscription: {}", err);
+                    }
+                }
+                Ok(status) => warn!(
+                    "The push service at {} refused the message: {}",
+                    subscription.endpoint, status
+                ),
+                Err(err) => warn!(
+                    "Failed to send the push message to {}: {}",
+                    subscription.endpoint, err
+                ),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read the keys of a subscription, the same way the browser writes them.
+fn builder(subscription: &PushSubscription) -> Result<WebPushBuilder, String> {
+    serde_json::to_value(subscription)
+        .and_then(serde_json::from_value)
+        .map_err(|err| format!("Invalid push subscription: {}", err))
+}
+
+/// `PushSubscription.toJSON()` from the browser, along with the CSRF token.
+#[derive(Deserialize)]
+struct SubscribeRequest {
+    endpoint: String,
+    keys: PushKeys,
+    #[serde(default)]
+    csrf_token: String,
+}
+
+impl std::fmt::Debug for SubscribeRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SubscribeRequest")
+            .field("endpoint", &self.endpoint)
+            .finish_non_exhaustive()
+    }
+}
+
+#[post("/push/subscribe")]
+#[tracing::instrument(level = "info", skip(push))]
+async fn subscribe(
+    request: Json<SubscribeRequest>,
+    push: Data<Push>,
+    user: ReqData<CurrentUser>,
+    csrf: ReqData<Csrf>,
+) -> actix_web::Result<impl Responder> {
+    csrf.check(&request.csrf_token)?;
+    let Some(settings) = push.settings() else {
+        return Err(ErrorNotFound("Push notifications are turned off"));
+    };
+    let request = request.into_inner();
+    let url = Url::parse(&request.endpoint)
+        .ok()
+        .filter(|url| url.scheme() == "https")
+        .ok_or_else(|| ErrorBadRequest("The push endpoint has to be an https URL"))?;
+    settings
+        .destinations
+        .check_url(&url)
+        .map_err(|err| ErrorBadRequest(format!("Push messages can't be sent to {}", err.0)))?;
+    let subscription = PushSubscription {
+        owner: user.username.clone(),
+        endpoint: request.endpoint,
+        keys: request.keys,
+        created_at: unix_now(),
+    };
+    builder(&subscription).map_err(ErrorBadRequest)?;
+    // A browser has one subscription, which goes to whoever subscribed last.
+    push.inner
+        .subscriptions
+        .put(tokens::hash_secret(&subscription.endpoint), &subscription)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Debug, Deserialize)]
+struct UnsubscribeRequest {
+    endpoint: String,
+    #[serde(default)]
+    csrf_token: String,
+}
+
+#[post("/push/unsubscribe")]
+#[tracing::instrument(level = "info", skip(push))]
+async fn unsubscribe(
+    request: Json<UnsubscribeRequest>,
+    push: Data<Push>,
+    user: ReqData<CurrentUser>,
+    csrf: ReqData<Csrf>,
+) -> actix_web::Result<impl Responder> {
+    csrf.check(&request.csrf_token)?;
+    let key = tokens::hash_secret(&request.endpoint);
+    let subscriptions = &push.inner.subscriptions;
+    let found = subscriptions
+        .get(&key)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    if found.is_some_and(|subscription| subscription.owner == user.username) {
+        subscriptions
+            .delete(&key)
+            .await
+            .map_err(ErrorInternalServerError)?;
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/sw.js")]
+#[tracing::instrument(level = "debug")]
+async fn service_worker() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/javascript")
+        // Browsers check for a new version of the worker on their own.
+        .insert_header(("Cache-Control", "no-cache"))
+        .body(SERVICE_WORKER)
+}
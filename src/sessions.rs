@@ -0,0 +1,121 @@
+//! Logging in through a login page instead of HTTP Basic authentication.
+//!
+//! Browsers remember Basic credentials until they are closed, so there is no
+//! way to log out. With the login page, the browser gets a session cookie
+//! instead, which logging out or the session expiring gets rid of. Basic
+//! authentication keeps working for scripts either way.
+use std::{str::FromStr, time::Duration};
+
+use actix_web::cookie::{self, Cookie, SameSite};
+
+use crate::{
+    proxy::ClientInfo,
+    store::{unix_now, Session, SessionStore, StoreError},
+    tokens,
+};
+
+/// The name of the session cookie.
+pub const COOKIE: &str = "http_drogue_session";
+
+/// How browsers log in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LoginMode {
+    /// The browser asks for the username and password.
+    #[default]
+    Basic,
+    /// Our own login page, with a session cookie.
+    Form,
+}
+
+impl FromStr for LoginMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "basic" => Ok(Self::Basic),
+            "form" => Ok(Self::Form),
+            _ => Err(format!("Unknown login mode {}, expected basic or form", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LoginConfig {
+    pub mode: LoginMode,
+    /// How long until a session expires and the user has to log in again.
+    pub session_lifetime: Duration,
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        LoginConfig {
+            mode: LoginMode::default(),
+            session_lifetime: Duration::from_secs(12 * 60 * 60),
+        }
+    }
+}
+
+/// Start a session for the user. Returns the value of the session cookie.
+pub async fn create(
+    store: &SessionStore,
+    username: &str,
+    lifetime: Duration,
+) -> Result<String, StoreError> {
+    // Clean up after users who never logged out.
+    let now = unix_now();
+    for (key, session) in store.scan().await? {
+        if session.expires_at <= now {
+            store.delete(&key).await?;
+        }
+    }
+    let secret = tokens::random_secret();
+    let session = Session {
+        username: username.to_string(),
+        created_at: now,
+        expires_at: now + lifetime.as_secs(),
+    };
+    store.put(&tokens::hash_secret(&secret), &session).await?;
+    Ok(secret)
+}
+
+/// Find the session for a session cookie, if it hasn't expired. Also returns
+/// the key the session is stored under, which identifies it without revealing
+/// the cookie.
+pub async fn find(store: &SessionStore, secret: &str) -> Option<(String, Session)> {
+    let key = tokens::hash_secret(secret);
+    let session = store.get(&key).await.ok()??;
+    if session.expires_at <= unix_now() {
+        let _ = store.delete(&key).await;
+        return None;
+    }
+    Some((key, session))
+}
+
+/// End the session for a session cookie.
+pub async fn delete(store: &SessionStore, secret: &str) -> Result<(), StoreError> {
+    store.delete(&tokens::hash_secret(secret)).await
+}
+
+/// Cookies are only sent to the app, not to anything else on the same host.
+fn cookie_path(client: &ClientInfo) -> String {
+    client.url("/")
+}
+
+/// The session cookie to send to the browser. Other sites can't make the
+/// browser send it along with their forms.
+pub fn cookie(secret: String, lifetime: Duration, client: &ClientInfo) -> Cookie<'static> {
+    Cookie::build(COOKIE, secret)
+        .path(cookie_path(client))
+        .http_only(true)
+        .secure(client.https)
+        .same_site(SameSite::Lax)
+        .max_age(cookie::time::Duration::seconds(lifetime.as_secs() as i64))
+        .finish()
+}
+
+/// A cookie that makes the browser forget the session cookie.
+pub fn removal_cookie(client: &ClientInfo) -> Cookie<'static> {
+    let mut cookie = Cookie::build(COOKIE, "").path(cookie_path(client)).finish();
+    cookie.make_removal();
+    cookie
+}
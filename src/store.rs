@@ -1,28 +1,486 @@
-use cuttlestore::Cuttlestore;
-use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub type DownloadProgressStore = Cuttlestore<Progress>;
+use cuttlestore::{Cuttlestore, CuttlestoreBuilder};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::info;
+use ulid::Ulid;
+
+use crate::{extract::ExtractProgress, hook::HookResult, metrics};
+
+pub type DownloadProgressStore = JsonStore<Progress>;
+pub type HistoryStore = JsonStore<HistoryEntry>;
+pub type TokenStore = JsonStore<ApiToken>;
+pub type UserStore = JsonStore<User>;
+pub type SessionStore = JsonStore<Session>;
+pub type PushSubscriptionStore = JsonStore<PushSubscription>;
+/// Random keys the app generates once and keeps across restarts.
+pub type SecretStore = JsonStore<String>;
+
+/// The current time, as seconds since the Unix epoch.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error("Failed to access the store: {0}")]
+    Store(Box<dyn std::error::Error + Send + Sync>),
+    #[error("Failed to encode or decode a stored value: {0}")]
+    Encoding(#[from] serde_json::Error),
+}
+
+/// Cuttlestore doesn't export its error type, so we box it.
+fn store_err<E: std::error::Error + Send + Sync + 'static>(err: E) -> StoreError {
+    StoreError::Store(Box::new(err))
+}
+
+/// A Cuttlestore that keeps its values as JSON.
+///
+/// Cuttlestore encodes values with bincode, which can't decode a value once a
+/// field has been added to its type. Storing JSON instead means new fields
+/// only need a `#[serde(default)]` to stay compatible with existing databases.
+pub struct JsonStore<T> {
+    store: Cuttlestore<String>,
+    phantom: PhantomData<T>,
+}
+
+impl<T> Clone for JsonStore<T> {
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for JsonStore<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.store.fmt(f)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> JsonStore<T> {
+    pub async fn get<Key: AsRef<str>>(&self, key: Key) -> Result<Option<T>, StoreError> {
+        let _timer = metrics::STORE_LATENCY
+            .with_label_values(&["get"])
+            .start_timer();
+        match self.store.get(key).await.map_err(store_err)? {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn put<Key: AsRef<str>>(&self, key: Key, value: &T) -> Result<(), StoreError> {
+        let _timer = metrics::STORE_LATENCY
+            .with_label_values(&["put"])
+            .start_timer();
+        self.store
+            .put(key, &serde_json::to_string(value)?)
+            .await
+            .map_err(store_err)?;
+        Ok(())
+    }
+
+    pub async fn delete<Key: AsRef<str>>(&self, key: Key) -> Result<(), StoreError> {
+        let _timer = metrics::STORE_LATENCY
+            .with_label_values(&["delete"])
+            .start_timer();
+        self.store.delete(key).await.map_err(store_err)?;
+        Ok(())
+    }
+
+    /// Get all the key and value pairs in the store.
+    pub async fn scan(&self) -> Result<Vec<(String, T)>, StoreError> {
+        use futures::StreamExt;
+
+        let _timer = metrics::STORE_LATENCY
+            .with_label_values(&["scan"])
+            .start_timer();
+        let mut stream = self.store.scan().await.map_err(store_err)?;
+        let mut values = Vec::new();
+        while let Some(pair) = stream.next().await {
+            let (key, value) = pair.map_err(store_err)?;
+            values.push((key, serde_json::from_str(&value)?));
+        }
+        Ok(values)
+    }
+}
+
+/// Options that change how a single download is performed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DownloadOptions {
+    /// The name of the file to save the download as, instead of the one
+    /// picked from the URL.
+    pub out: Option<String>,
+    /// A folder to save the download into, relative to the download folder.
+    pub dir: Option<String>,
+    /// Extra headers to send with the download request.
+    pub headers: Vec<(String, String)>,
+    /// Whether to extract the file if it is an archive. Follows the
+    /// configuration if not set.
+    pub extract: Option<bool>,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Progress {
+    /// The ULID that the download is stored under.
+    #[serde(default)]
+    pub id: String,
     pub target_file: Option<String>,
     pub failed: bool,
+    /// Paused downloads are not started until they are retried.
+    #[serde(default)]
+    pub paused: bool,
     pub url: String,
     pub progress: u64,
     pub total: Option<u64>,
     /// Bytes per second.
     pub speed: f64,
+    #[serde(default)]
+    pub options: DownloadOptions,
+    /// When the download first started, as a Unix timestamp.
+    #[serde(default)]
+    pub started_at: Option<u64>,
+    /// The user who added the download.
+    #[serde(default)]
+    pub owner: String,
+    /// The owner's folder, relative to the download folder. Empty if the
+    /// owner downloads to the download folder itself.
+    #[serde(default)]
+    pub base_dir: String,
+    /// Why the download failed, if it did.
+    #[serde(default)]
+    pub error: Option<String>,
+    /// How the post-completion hook went, if it failed the download.
+    #[serde(default)]
+    pub hook: Option<HookResult>,
+    /// How far along extracting the file is, while it is being extracted.
+    #[serde(skip)]
+    pub extracting: Option<ExtractProgress>,
+    /// How much of the file is known to be on the disk, which is where the
+    /// download resumes from. Preallocated files are as large as the whole
+    /// download from the start, so their length says nothing. Downloads from
+    /// before this was recorded go by the length of the file.
+    #[serde(default)]
+    pub verified: Option<u64>,
+}
+
+/// A download that has completed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// The ID the download had while it was in progress.
+    pub id: String,
+    pub url: String,
+    /// Where the file was saved, relative to the download folder.
+    pub path: String,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// How long the download took in seconds, including any retries.
+    pub duration: u64,
+    /// Average bytes per second.
+    pub speed: f64,
+    /// The SHA-256 hash of the file, hex encoded.
+    pub sha256: String,
+    /// When the download completed, as a Unix timestamp.
+    pub completed_at: u64,
+    /// The options the download used, so it can be downloaded again.
+    pub options: DownloadOptions,
+    /// The user who added the download.
+    #[serde(default)]
+    pub owner: String,
+    /// How the post-completion hook went, if there is one.
+    #[serde(default)]
+    pub hook: Option<HookResult>,
+    /// The folder the file was extracted into, if it was an archive that was
+    /// extracted.
+    #[serde(default)]
+    pub extracted_to: Option<String>,
+}
+
+/// What an API token is allowed to do. Each scope allows everything the
+/// ones before it do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TokenScope {
+    /// Look at downloads and the history.
+    ReadOnly,
+    /// Add new downloads as well.
+    Add,
+    /// Anything the API and the web page can do, except managing tokens.
+    Full,
+}
+
+/// A token that scripts can use to authenticate instead of the password.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: String,
+    /// A name to tell tokens apart, picked by the user.
+    pub name: String,
+    pub scope: TokenScope,
+    /// The SHA-256 hash of the token's secret, hex encoded.
+    pub hash: String,
+    /// When the token was created, as a Unix timestamp.
+    pub created_at: u64,
+    /// When the token stops working, as a Unix timestamp.
+    pub expires_at: Option<u64>,
+    /// The user the token acts as. It can't do anything the user can't.
+    #[serde(default)]
+    pub owner: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Can see and manage everyone's downloads, and manage users.
+    Admin,
+    /// Can add and manage their own downloads.
+    User,
+    /// Can see everyone's downloads, but not change anything.
+    Viewer,
+}
+
+/// A user account. The admin account set up through the environment is not
+/// stored here.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub username: String,
+    pub role: Role,
+    /// The password, hashed in the PHC string format.
+    pub password_hash: String,
+    /// The most the user's folder can hold, in bytes. New downloads are
+    /// refused once it is full.
+    pub quota: Option<u64>,
+    /// When the account was created, as a Unix timestamp.
+    pub created_at: u64,
+}
+
+/// A browser logged in through the login page. Stored under the SHA-256 hash
+/// of the session cookie.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub username: String,
+    /// When the user logged in, as a Unix timestamp.
+    pub created_at: u64,
+    /// When the user has to log in again, as a Unix timestamp.
+    pub expires_at: u64,
 }
 
 impl Progress {
-    pub fn default_with(url: String) -> Self {
+    pub fn default_with(id: String, url: String) -> Self {
         Progress {
+            id,
             target_file: None,
             failed: false,
+            paused: false,
             url,
             progress: 0,
             total: None,
             speed: 0f64,
+            options: DownloadOptions::default(),
+            started_at: None,
+            owner: String::new(),
+            base_dir: String::new(),
+            error: None,
+            hook: None,
+            extracting: None,
+            verified: Some(0),
+        }
+    }
+}
+
+/// The bincode encoded records that were stored before we switched to JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LegacyProgress {
+    target_file: Option<String>,
+    failed: bool,
+    url: String,
+    progress: u64,
+    total: Option<u64>,
+    speed: f64,
+}
+
+/// The version of the data layout in the store. Bump this and add a step to
+/// `migrate` whenever existing records need to be rewritten.
+const SCHEMA_VERSION: u32 = 3;
+
+/// A browser that gets push notifications about a user's downloads. Stored
+/// under the SHA-256 hash of the endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushSubscription {
+    pub owner: String,
+    /// Where the push service takes messages for the browser.
+    pub endpoint: String,
+    pub keys: PushKeys,
+    /// When the browser subscribed, as a Unix timestamp.
+    pub created_at: u64,
+}
+
+/// The keys the browser gave to encrypt messages with, base64url encoded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushKeys {
+    pub p256dh: String,
+    pub auth: String,
+}
+
+/// All the stores the app uses, sharing a single connection.
+pub struct Stores {
+    pub progress: DownloadProgressStore,
+    pub history: HistoryStore,
+    pub tokens: TokenStore,
+    pub users: UserStore,
+    pub sessions: SessionStore,
+    pub secrets: SecretStore,
+    pub push_subscriptions: PushSubscriptionStore,
+}
+
+impl Stores {
+    /// Open the stores, migrating any old data. Anything stored from before
+    /// there were user accounts is given to `default_owner`.
+    pub async fn open(conn: &str, default_owner: &str) -> Result<Self, StoreError> {
+        let connection = CuttlestoreBuilder::new(conn)
+            .finish_connection()
+            .await
+            .map_err(store_err)?;
+        let meta: JsonStore<u32> = JsonStore {
+            store: connection.make("meta").await.map_err(store_err)?,
+            phantom: PhantomData,
+        };
+        let stores = Stores {
+            progress: JsonStore {
+                store: connection.make("downloads").await.map_err(store_err)?,
+                phantom: PhantomData,
+            },
+            history: JsonStore {
+                store: connection.make("history").await.map_err(store_err)?,
+                phantom: PhantomData,
+            },
+            tokens: JsonStore {
+                store: connection.make("tokens").await.map_err(store_err)?,
+                phantom: PhantomData,
+            },
+            users: JsonStore {
+                store: connection.make("users").await.map_err(store_err)?,
+                phantom: PhantomData,
+            },
+            sessions: JsonStore {
+                store: connection.make("sessions").await.map_err(store_err)?,
+                phantom: PhantomData,
+            },
+            secrets: JsonStore {
+                store: connection.make("secrets").await.map_err(store_err)?,
+                phantom: PhantomData,
+            },
+            push_subscriptions: JsonStore {
+                store: connection
+                    .make("push_subscriptions")
+                    .await
+                    .map_err(store_err)?,
+                phantom: PhantomData,
+            },
+        };
+
+        let version = meta.get("schema").await?.unwrap_or(0);
+        if version < SCHEMA_VERSION {
+            stores.migrate(conn, version, default_owner).await?;
+            meta.put("schema", &SCHEMA_VERSION).await?;
+        }
+
+        Ok(stores)
+    }
+
+    async fn migrate(&self, conn: &str, from: u32, default_owner: &str) -> Result<(), StoreError> {
+        if from < 1 {
+            // Version 0 stored bincode encoded records, keyed by the URL
+            // without any prefix. Nothing else could have been in the store
+            // at the time, so it's safe to decode everything.
+            use futures::StreamExt;
+
+            let legacy: Cuttlestore<LegacyProgress> =
+                Cuttlestore::new(conn).await.map_err(store_err)?;
+            let records = legacy
+                .scan()
+                .await
+                .map_err(store_err)?
+                .collect::<Vec<_>>()
+                .await
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(store_err)?;
+            info!(
+                "Migrating {} downloads to the new store format",
+                records.len()
+            );
+            for (key, record) in records {
+                self.progress
+                    .put(
+                        &key,
+                        &Progress {
+                            id: String::new(),
+                            target_file: record.target_file,
+                            failed: record.failed,
+                            paused: false,
+                            url: record.url,
+                            progress: record.progress,
+                            total: record.total,
+                            speed: record.speed,
+                            options: DownloadOptions::default(),
+                            started_at: None,
+                            owner: String::new(),
+                            base_dir: String::new(),
+                            error: None,
+                            hook: None,
+                            extracting: None,
+                            verified: None,
+                        },
+                    )
+                    .await?;
+                legacy.delete(&key).await.map_err(store_err)?;
+            }
+        }
+        if from < 2 {
+            // Version 1 keyed downloads by their URL, move them to ULIDs.
+            for (key, record) in self.progress.scan().await? {
+                if Ulid::from_string(&key).is_ok() {
+                    continue;
+                }
+                let id = Ulid::new().to_string();
+                info!("Moving download of {} to ID {}", record.url, id);
+                self.progress
+                    .put(
+                        &id,
+                        &Progress {
+                            id: id.clone(),
+                            ..record
+                        },
+                    )
+                    .await?;
+                self.progress.delete(&key).await?;
+            }
+        }
+        if from < 3 {
+            // Version 2 had a single user, so everything belongs to them.
+            info!("Giving existing downloads and tokens to {}", default_owner);
+            for (key, record) in self.progress.scan().await? {
+                let owner = default_owner.to_string();
+                self.progress
+                    .put(&key, &Progress { owner, ..record })
+                    .await?;
+            }
+            for (key, entry) in self.history.scan().await? {
+                let owner = default_owner.to_string();
+                self.history
+                    .put(&key, &HistoryEntry { owner, ..entry })
+                    .await?;
+            }
+            for (key, token) in self.tokens.scan().await? {
+                let owner = default_owner.to_string();
+                self.tokens.put(&key, &ApiToken { owner, ..token }).await?;
+            }
         }
+        Ok(())
     }
 }
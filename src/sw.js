@@ -0,0 +1,23 @@
+// The service worker for push notifications. It shows the messages the server
+// sends when downloads complete or fail, and opens the page when one of them
+// is clicked.
+self.addEventListener("push", (event) => {
+  const message = event.data ? event.data.json() : {};
+  event.waitUntil(
+    self.registration.showNotification(message.title || "Http Drogue", {
+      body: message.body,
+      tag: message.tag,
+    })
+  );
+});
+
+self.addEventListener("notificationclick", (event) => {
+  event.notification.close();
+  const page = self.registration.scope;
+  event.waitUntil(
+    clients.matchAll({ type: "window" }).then((windows) => {
+      const open = windows.find((window) => window.url.startsWith(page));
+      return open ? open.focus() : clients.openWindow(page);
+    })
+  );
+});
@@ -0,0 +1,132 @@
+//! Serving HTTPS directly, so small installs don't need a reverse proxy.
+//!
+//! The certificate and key are read from PEM files. The files are checked
+//! every minute and read again when they change, so renewing the certificate
+//! doesn't need a restart.
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey, ServerConfig,
+};
+use rustls_pemfile::Item;
+use tracing::{error, info};
+
+/// How often to check whether the certificate or key changed.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum TlsError {
+    #[error("Failed to read {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("No certificates found in {0}")]
+    NoCertificates(PathBuf),
+    #[error("No private key found in {0}")]
+    NoKey(PathBuf),
+    #[error("Unsupported private key in {0}: {1}")]
+    UnsupportedKey(PathBuf, sign::SignError),
+}
+
+/// Where the certificate and its private key are.
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    /// The certificate, followed by any intermediate certificates.
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl TlsFiles {
+    fn load(&self) -> Result<CertifiedKey, TlsError> {
+        let read_err = |path: &Path| {
+            let path = path.to_path_buf();
+            move |err| TlsError::Read(path, err)
+        };
+        let mut reader = BufReader::new(File::open(&self.cert).map_err(read_err(&self.cert))?);
+        let certs = rustls_pemfile::certs(&mut reader).map_err(read_err(&self.cert))?;
+        if certs.is_empty() {
+            return Err(TlsError::NoCertificates(self.cert.clone()));
+        }
+
+        let mut reader = BufReader::new(File::open(&self.key).map_err(read_err(&self.key))?);
+        let key = rustls_pemfile::read_all(&mut reader)
+            .map_err(read_err(&self.key))?
+            .into_iter()
+            .find_map(|item| match item {
+                Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(key),
+                _ => None,
+            })
+            .ok_or_else(|| TlsError::NoKey(self.key.clone()))?;
+        let key = sign::any_supported_type(&PrivateKey(key))
+            .map_err(|err| TlsError::UnsupportedKey(self.key.clone(), err))?;
+
+        Ok(CertifiedKey::new(
+            certs.into_iter().map(Certificate).collect(),
+            key,
+        ))
+    }
+
+    /// When the files were last changed, to tell if they need reloading.
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let cert = fs::metadata(&self.cert).and_then(|m| m.modified()).ok()?;
+        let key = fs::metadata(&self.key).and_then(|m| m.modified()).ok()?;
+        Some((cert, key))
+    }
+}
+
+/// Hands out the current certificate, which can be swapped while running.
+struct CertResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Load the certificate and key, and keep watching them for changes.
+pub fn server_config(files: TlsFiles) -> Result<ServerConfig, TlsError> {
+    let modified = files.modified();
+    let resolver = Arc::new(CertResolver {
+        current: RwLock::new(Arc::new(files.load()?)),
+    });
+    tokio::spawn(watch(files, resolver.clone(), modified));
+    Ok(ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver))
+}
+
+async fn watch(
+    files: TlsFiles,
+    resolver: Arc<CertResolver>,
+    mut modified: Option<(SystemTime, SystemTime)>,
+) {
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+        let now = files.modified();
+        if now == modified {
+            continue;
+        }
+        // The files may be in the middle of being replaced, in which case
+        // this fails and we try again on the next check.
+        match files.load() {
+            Ok(key) => {
+                *resolver.current.write().unwrap() = Arc::new(key);
+                modified = now;
+                info!("Reloaded the TLS certificate from {}", files.cert.display());
+            }
+            Err(err) => error!(
+                "Failed to reload the TLS certificate, still using the old one: {}",
+                err
+            ),
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! API tokens, so scripts don't need to know the password.
+//!
+//! A token looks like `hd_<id>_<secret>`. Only a hash of the secret is kept in
+//! the store, so the token is shown once when it is created and can't be
+//! recovered afterwards. The secret is a long random value rather than
+//! something a person picked, so a fast hash is enough here.
+use actix_web::http::Method;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use ulid::Ulid;
+
+use crate::store::{unix_now, ApiToken, TokenScope, TokenStore};
+
+const PREFIX: &str = "hd_";
+
+/// Hash a random secret for storage.
+pub fn hash_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+/// A new random secret, hex encoded.
+pub fn random_secret() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Create a new token. Returns the token to store, and the token to hand to
+/// the user.
+pub fn generate(
+    owner: String,
+    name: String,
+    scope: TokenScope,
+    expires_at: Option<u64>,
+) -> (ApiToken, String) {
+    let secret = random_secret();
+    let id = Ulid::new().to_string();
+    let token = format!("{}{}_{}", PREFIX, id, secret);
+    (
+        ApiToken {
+            id,
+            name,
+            scope,
+            hash: hash_secret(&secret),
+            created_at: unix_now(),
+            expires_at,
+            owner,
+        },
+        token,
+    )
+}
+
+/// Find the stored token matching the one the client sent, if it is valid
+/// and has not expired.
+pub async fn verify(store: &TokenStore, token: &str) -> Option<ApiToken> {
+    let (id, secret) = token.strip_prefix(PREFIX)?.split_once('_')?;
+    let stored = store.get(id).await.ok()??;
+    if stored
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= unix_now())
+    {
+        return None;
+    }
+    constant_time_eq(&hash_secret(secret), &stored.hash).then_some(stored)
+}
+
+/// Compare without stopping at the first difference, so the time taken
+/// doesn't reveal how much of the secret matched.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// The scope a token needs to make this request. Returns `None` if tokens
+/// can't be used for it at all, which is the case for managing tokens and
+/// users, and for push subscriptions and logging out, which only browsers do.
+pub fn required_scope(method: &Method, path: &str) -> Option<TokenScope> {
+    let management = ["/tokens", "/users", "/webhooks", "/push", "/logout"]
+        .iter()
+        .any(|page| path == *page || path.starts_with(&format!("{}/", page)));
+    if management {
+        return None;
+    }
+    if method == Method::GET || method == Method::HEAD {
+        return Some(TokenScope::ReadOnly);
+    }
+    match path {
+        "/api/v1/downloads" | "/request_download" | "/request_batch" | "/history/redownload" => {
+            Some(TokenScope::Add)
+        }
+        _ => Some(TokenScope::Full),
+    }
+}
@@ -0,0 +1,322 @@
+//! User accounts, and what each role is allowed to do.
+//!
+//! The admin account is set up in the configuration so there is always a way
+//! in. Other accounts are kept in the store, with their passwords hashed
+//! using Argon2. Password hashes are PHC strings, and both Argon2 and scrypt
+//! hashes are accepted when checking a password.
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use actix_web::{http::Method, web};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, SaltString},
+    Argon2,
+};
+use lazy_static::lazy_static;
+use ractor::{call, ActorRef};
+use scrypt::Scrypt;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    download_actor::{Coordinator, CoordinatorMsg, Owner},
+    store::{Role, TokenScope, User},
+    tokens,
+};
+
+/// The admin account from the configuration.
+#[derive(Debug)]
+pub struct AdminAccount {
+    pub username: String,
+    /// A PHC string. Reloading the configuration can change it.
+    pub password_hash: RwLock<String>,
+}
+
+/// The user making a request. The authentication middleware adds this to
+/// every request it lets through.
+#[derive(Debug, Clone)]
+pub struct CurrentUser {
+    pub username: String,
+    pub role: Role,
+    /// The user's folder, relative to the download folder.
+    pub base_dir: String,
+    pub quota: Option<u64>,
+}
+
+impl CurrentUser {
+    /// The admin account from the configuration. It downloads into the download
+    /// folder itself, like before there were user accounts.
+    pub fn admin(username: &str) -> Self {
+        CurrentUser {
+            username: username.to_string(),
+            role: Role::Admin,
+            base_dir: String::new(),
+            quota: None,
+        }
+    }
+
+    /// Whether the user can see a download or history entry.
+    pub fn can_see(&self, owner: &str) -> bool {
+        self.role != Role::User || owner == self.username
+    }
+
+    /// Whether the user can change or remove a download or history entry.
+    pub fn can_manage(&self, owner: &str) -> bool {
+        match self.role {
+            Role::Admin => true,
+            Role::User => owner == self.username,
+            Role::Viewer => false,
+        }
+    }
+
+    /// Who new downloads added by this user belong to.
+    pub fn owner(&self) -> Owner {
+        Owner {
+            username: self.username.clone(),
+            base_dir: self.base_dir.clone(),
+        }
+    }
+
+    /// Whether the user may make this request. Requests made with a token are
+    /// also limited to the token's scope.
+    pub fn may_request(&self, method: &Method, path: &str, token: Option<TokenScope>) -> bool {
+        let admin_only = ["/users", "/webhooks"]
+            .iter()
+            .any(|page| path == *page || path.starts_with(&format!("{}/", page)));
+        if admin_only && self.role != Role::Admin {
+            return false;
+        }
+        let max_scope = match self.role {
+            Role::Viewer => TokenScope::ReadOnly,
+            Role::Admin | Role::User => TokenScope::Full,
+        };
+        match (tokens::required_scope(method, path), token) {
+            (Some(required), Some(token)) => max_scope.min(token) >= required,
+            (Some(required), None) => max_scope >= required,
+            (None, Some(_)) => false,
+            (None, None) => true,
+        }
+    }
+}
+
+impl From<&User> for CurrentUser {
+    fn from(user: &User) -> Self {
+        CurrentUser {
+            username: user.username.clone(),
+            role: user.role,
+            base_dir: user.username.clone(),
+            quota: user.quota,
+        }
+    }
+}
+
+/// Usernames are also used as folder names, so they are kept simple.
+pub fn validate_username(username: &str) -> Result<(), String> {
+    let valid = !username.is_empty()
+        && username.len() <= 32
+        && !username.starts_with('.')
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "Username {} must be up to 32 letters, numbers, '_', '-' or '.', and not start with '.'",
+            username
+        ))
+    }
+}
+
+/// Hash the password into a PHC string.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| err.to_string())
+}
+
+/// Check that a PHC string is a password hash we know how to verify.
+pub fn validate_hash(hash: &str) -> Result<(), String> {
+    let parsed = PasswordHash::new(hash).map_err(|err| err.to_string())?;
+    match parsed.algorithm.as_str() {
+        "argon2id" | "argon2i" | "argon2d" | "scrypt" => Ok(()),
+        other => Err(format!(
+            "Unsupported password hash algorithm {}, use argon2id or scrypt",
+            other
+        )),
+    }
+}
+
+lazy_static! {
+    /// Checked against when there is no such user, so that takes as long as a
+    /// wrong password and doesn't tell which usernames exist.
+    static ref DUMMY_HASH: String =
+        hash_password("not anyone's password").expect("hashing with the defaults works");
+}
+
+/// Make the dummy hash up front, so the first check for a user that doesn't
+/// exist doesn't take longer than the rest.
+pub fn prepare_dummy_hash() {
+    lazy_static::initialize(&DUMMY_HASH);
+}
+
+/// Check a password for a user that doesn't exist. Always fails, but takes
+/// as long as checking a real one.
+pub async fn verify_dummy_password(password: String) -> bool {
+    let _ = web::block(move || check_password(&password, &DUMMY_HASH)).await;
+    false
+}
+
+/// Check the password against a PHC string. Hashing is slow on purpose, so
+/// this runs on the blocking thread pool.
+pub async fn verify_password(password: String, hash: String) -> bool {
+    web::block(move || check_password(&password, &hash))
+        .await
+        .unwrap_or(false)
+}
+
+fn check_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .map(|hash| {
+            hash.verify_password(&[&Argon2::default(), &Scrypt], password.as_bytes())
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+/// How long a correct password is remembered.
+const PASSWORD_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Passwords that were checked recently. Scripts using Basic authentication
+/// send the password with every request, and hashing it each time would make
+/// every request slow. Only a SHA-256 of the password is kept, along with the
+/// hash it was checked against, so an entry is dropped once the user's hash
+/// changes.
+#[derive(Debug, Default)]
+pub struct PasswordCache {
+    verified: Mutex<HashMap<String, CachedPassword>>,
+}
+
+#[derive(Debug)]
+struct CachedPassword {
+    digest: String,
+    hash: String,
+    verified_at: Instant,
+}
+
+impl PasswordCache {
+    /// Check the user's password against their hash, skipping the slow check
+    /// if the same password was correct a moment ago.
+    pub async fn verify(&self, username: &str, password: String, hash: String) -> bool {
+        let digest = format!("{:x}", Sha256::digest(password.as_bytes()));
+        {
+            let mut verified = self.verified.lock().unwrap();
+            match verified.get(username) {
+                Some(cached)
+                    if cached.hash != hash || cached.verified_at.elapsed() > PASSWORD_CACHE_TTL =>
+                {
+                    verified.remove(username);
+                }
+                Some(cached) if tokens::constant_time_eq(&cached.digest, &digest) => return true,
+                _ => {}
+            }
+        }
+        if !verify_password(password, hash.clone()).await {
+            return false;
+        }
+        let mut verified = self.verified.lock().unwrap();
+        verified.retain(|_, cached| cached.verified_at.elapsed() <= PASSWORD_CACHE_TTL);
+        verified.insert(
+            username.to_string(),
+            CachedPassword {
+                digest,
+                hash,
+                verified_at: Instant::now(),
+            },
+        );
+        true
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum QuotaError {
+    #[error("Your download folder is full, it is using {used} of {quota} bytes")]
+    Exceeded { used: u64, quota: u64 },
+    #[error("Failed to check the download folder size: {0}")]
+    Internal(String),
+}
+
+/// Total size of the files in a folder and the folders inside it.
+fn folder_size(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => folder_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Refuse new downloads if the user's folder is full. Downloads in progress
+/// count towards the quota too.
+pub async fn check_quota(
+    user: &CurrentUser,
+    coordinator: &ActorRef<Coordinator>,
+) -> Result<(), QuotaError> {
+    let Some(quota) = user.quota else {
+        return Ok(());
+    };
+    let downloads = call!(coordinator, CoordinatorMsg::List)
+        .map_err(|err| QuotaError::Internal(err.to_string()))?;
+    let in_progress: u64 = downloads
+        .iter()
+        .filter(|download| download.owner == user.username)
+        .map(|download| download.progress)
+        .sum();
+    let folder = PathBuf::from(&user.base_dir);
+    let completed = web::block(move || folder_size(&folder))
+        .await
+        .map_err(|err| QuotaError::Internal(err.to_string()))?;
+    let used = completed + in_progress;
+    if used >= quota {
+        return Err(QuotaError::Exceeded { used, quota });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(role: Role) -> CurrentUser {
+        CurrentUser {
+            username: "someone".to_string(),
+            role,
+            base_dir: "someone".to_string(),
+            quota: None,
+        }
+    }
+
+    #[test]
+    fn viewer_may_log_out() {
+        let viewer = user(Role::Viewer);
+        assert!(viewer.may_request(&Method::POST, "/logout", None));
+        assert!(!viewer.may_request(&Method::POST, "/request_download", None));
+    }
+
+    #[test]
+    fn tokens_may_not_log_out() {
+        let admin = user(Role::Admin);
+        assert!(admin.may_request(&Method::POST, "/logout", None));
+        assert!(!admin.may_request(&Method::POST, "/logout", Some(TokenScope::Full)));
+    }
+}
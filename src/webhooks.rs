@@ -0,0 +1,336 @@
+//! Webhooks, to let chat bots and home automation know what downloads are up
+//! to.
+//!
+//! Each webhook subscribes to some events, and gets a JSON payload POSTed to
+//! it when they happen. If the webhook has a secret, the payload is signed with
+//! HMAC-SHA256 and the signature sent in the `X-Drogue-Signature` header, so
+//! the receiver can tell the payload came from us. Failed deliveries are tried
+//! again a few times, waiting longer each time. The most recent deliveries are
+//! kept in memory so they can be checked on the webhooks page.
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use hmac::{Hmac, Mac};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::{debug, warn};
+use ulid::Ulid;
+
+use crate::{
+    download_actor::final_path,
+    store::{unix_now, Progress},
+};
+
+/// How many times to try delivering a payload before giving up.
+const MAX_ATTEMPTS: u32 = 5;
+/// How long to wait after the first failed attempt. This doubles after every
+/// attempt.
+const FIRST_BACKOFF: Duration = Duration::from_secs(10);
+/// How long to wait for the webhook to respond.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// How many deliveries to keep for the webhooks page.
+const RECENT_DELIVERIES: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookEvent {
+    /// A download was added.
+    Added,
+    Completed,
+    /// A download failed and won't be restarted on its own.
+    Failed,
+    /// A download failed and was restarted, either on its own or by someone
+    /// retrying it.
+    Retried,
+}
+
+impl FromStr for WebhookEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "added" => Ok(Self::Added),
+            "completed" => Ok(Self::Completed),
+            "failed" => Ok(Self::Failed),
+            "retried" => Ok(Self::Retried),
+            _ => Err(format!(
+                "Unknown event {}, expected added, completed, failed or retried",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for WebhookEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Added => "added",
+            Self::Completed => "completed",
+            Self::Failed => "failed",
+            Self::Retried => "retried",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Clone)]
+pub struct Webhook {
+    pub url: Url,
+    /// The events to send, or all of them if empty.
+    pub events: Vec<WebhookEvent>,
+    pub secret: Option<String>,
+}
+
+impl std::fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Webhook")
+            .field("url", &self.url.as_str())
+            .field("events", &self.events)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Webhook {
+    fn wants(&self, event: WebhookEvent) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    /// Still being tried.
+    Pending,
+    Delivered,
+    /// Gave up after `MAX_ATTEMPTS`.
+    Failed,
+}
+
+/// An attempt to send a payload to a webhook, for troubleshooting.
+#[derive(Debug, Clone, Serialize)]
+pub struct Delivery {
+    pub id: String,
+    pub url: String,
+    pub event: WebhookEvent,
+    pub download_id: String,
+    pub attempts: u32,
+    pub status: DeliveryStatus,
+    /// The response status of the last attempt, or why it failed.
+    pub last_result: Option<String>,
+    /// When the delivery was last attempted, as a Unix timestamp.
+    pub updated_at: u64,
+}
+
+/// What gets POSTed to the webhooks.
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    event: WebhookEvent,
+    /// When the event happened, as a Unix timestamp.
+    timestamp: u64,
+    download: &'a Progress,
+    /// Where the file is saved once it completes, relative to the download
+    /// folder.
+    path: String,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    webhooks: RwLock<Vec<Webhook>>,
+    deliveries: Mutex<VecDeque<Delivery>>,
+    client: Client,
+}
+
+/// Sends payloads to the webhooks in the background. Cloning it is cheap.
+#[derive(Debug, Clone, Default)]
+pub struct Webhooks {
+    inner: Arc<Inner>,
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+impl Webhooks {
+    pub fn new(webhooks: Vec<Webhook>) -> Self {
+        let client = Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        Webhooks {
+            inner: Arc::new(Inner {
+                webhooks: RwLock::new(webhooks),
+                deliveries: Mutex::default(),
+                client,
+            }),
+        }
+    }
+
+    /// Replace the webhooks, when the configuration is reloaded. Deliveries
+    /// already under way continue.
+    pub fn set(&self, webhooks: Vec<Webhook>) {
+        *self.inner.webhooks.write().unwrap() = webhooks;
+    }
+
+    pub fn list(&self) -> Vec<Webhook> {
+        self.inner.webhooks.read().unwrap().clone()
+    }
+
+    /// The most recent deliveries, newest first.
+    pub fn recent_deliveries(&self) -> Vec<Delivery> {
+        self.inner
+            .deliveries
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    /// Send the event to the webhooks that want it. This returns right away,
+    /// the payloads are delivered in the background.
+    pub fn send(&self, event: WebhookEvent, record: &Progress) {
+        let webhooks: Vec<Webhook> = self
+            .inner
+            .webhooks
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|webhook| webhook.wants(event))
+            .cloned()
+            .collect();
+        if webhooks.is_empty() {
+            return;
+        }
+        // The request headers could have credentials in them.
+        let mut download = record.clone();
+        download.options.headers.clear();
+        let payload = Payload {
+            event,
+            timestamp: unix_now(),
+            download: &download,
+            path: final_path(record).to_string_lossy().to_string(),
+        };
+        let body = match serde_json::to_vec(&payload) {
+            Ok(body) => body,
+            Err(err) => {
+                warn!("Failed to encode the webhook payload: {}", err);
+                return;
+            }
+        };
+        for webhook in webhooks {
+            tokio::spawn(
+                self.clone()
+                    .deliver(webhook, event, record.id.clone(), body.clone()),
+            );
+        }
+    }
+
+    fn update_delivery(&self, delivery: Delivery) {
+        let mut deliveries = self.inner.deliveries.lock().unwrap();
+        match deliveries.iter_mut().find(|d| d.id == delivery.id) {
+            Some(existing) => *existing = delivery,
+            None => {
+                if deliveries.len() >= RECENT_DELIVERIES {
+                    deliveries.pop_front();
+                }
+                deliveries.push_back(delivery);
+            }
+        }
+    }
+
+    async fn attempt(
+        &self,
+        webhook: &Webhook,
+        delivery: &Delivery,
+        body: &[u8],
+    ) -> Result<String, String> {
+        let mut request = self
+            .inner
+            .client
+            .post(webhook.url.clone())
+            .header("Content-Type", "application/json")
+            .header("X-Drogue-Event", delivery.event.to_string())
+            .header("X-Drogue-Delivery", &delivery.id);
+        if let Some(secret) = &webhook.secret {
+            request = request.header(
+                "X-Drogue-Signature",
+                format!("sha256={}", sign(secret, body)),
+            );
+        }
+        let response = request
+            .body(body.to_vec())
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(status.to_string())
+        } else {
+            Err(status.to_string())
+        }
+    }
+
+    async fn deliver(
+        self,
+        webhook: Webhook,
+        event: WebhookEvent,
+        download_id: String,
+        body: Vec<u8>,
+    ) {
+        let mut delivery = Delivery {
+            id: Ulid::new().to_string(),
+            url: webhook.url.to_string(),
+            event,
+            download_id,
+            attempts: 0,
+            status: DeliveryStatus::Pending,
+            last_result: None,
+            updated_at: unix_now(),
+        };
+        self.update_delivery(delivery.clone());
+
+        let mut backoff = FIRST_BACKOFF;
+        loop {
+            let result = self.attempt(&webhook, &delivery, &body).await;
+            delivery.attempts += 1;
+            delivery.updated_at = unix_now();
+            match result {
+                Ok(status) => {
+                    debug!("Delivered {} event to {}", event, delivery.url);
+                    delivery.status = DeliveryStatus::Delivered;
+                    delivery.last_result = Some(status);
+                }
+                Err(err) => {
+                    warn!(
+                        "Failed to deliver {} event to {}, attempt {}: {}",
+                        event, delivery.url, delivery.attempts, err
+                    );
+                    if delivery.attempts >= MAX_ATTEMPTS {
+                        delivery.status = DeliveryStatus::Failed;
+                    }
+                    delivery.last_result = Some(err);
+                }
+            }
+            self.update_delivery(delivery.clone());
+            if delivery.status != DeliveryStatus::Pending {
+                return;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+}